@@ -1,394 +1,4397 @@
-// @todo Improve handling of deprecation warnings from outputs in get_active_plugins, get_wordpress_version, update_in_steps.
+//! Updates a WordPress installation step-by-step, as run by `main_loop`, `check` and `restore`.
+//!
+//! For building other workflows, the primitives `stream_command`, `get_active_plugins`,
+//! `activate_plugins`, `backup_database`, `get_wordpress_version`, `fetch_updates` and
+//! `git_add_commit` are also public, along with the types they take. They all run their commands
+//! through a `CommandRunner` on the installation at `wordpress_path`.
 
-use clap::Parser;
-use serde::Deserialize;
+use chrono::{
+	format::{Item, StrftimeItems},
+	Local,
+};
+#[cfg(feature = "min-age")]
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use clap::{parser::ValueSource, ArgAction, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use clap_complete::Shell;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::{debug, error, info, trace, warn, LevelFilter};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
+	cell::RefCell,
+	cmp::Reverse,
+	collections::HashMap,
+	env,
 	error::Error,
-	fs,
-	io::{self, BufRead, BufReader, ErrorKind},
+	ffi::OsString,
+	fs::{self, File, OpenOptions},
+	io::{self, BufRead, BufReader, IsTerminal, Read, Seek, SeekFrom, Write},
 	ops::Deref,
-	path::Path,
-	process::{Command, Stdio},
+	path::{Path, PathBuf},
+	process::{Child, Command, ExitStatus, Output, Stdio},
 	str,
-	time::{SystemTime, UNIX_EPOCH},
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		Mutex, PoisonError,
+	},
+	thread::{self, JoinHandle},
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-const JSON_START: &str = "[{\"";
+const DATE_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+
+/// Config file read from the working directory when --config isn't given.
+const CONFIG_FILE_NAME: &str = "updatewp.toml";
 
 pub type OrError<A> = Result<A, Box<dyn Error>>;
 
-fn get_json(string: &str) -> Option<&str> {
-	if string.starts_with(JSON_START) {
-		Some(string)
-	} else if let Some(index) = string.find(JSON_START) {
-		Some(&string[index..])
-	} else {
-		None
+struct Json<'a, A> {
+	value: A,
+	before: &'a str,
+	json: &'a str,
+	after: &'a str,
+}
+
+/// Parses the first JSON array or object in `string` that's on lines of its own as `A`, skipping
+/// any surrounding text such as PHP deprecation warnings, along with fragments of JSON within their
+/// lines, like "[]". Yields `A::default()` when there is none, but fails if it doesn't deserialise
+/// as `A`, so that a change in WP-CLI's output isn't mistaken for it returning nothing.
+fn get_json<A: DeserializeOwned + Default>(string: &str) -> OrError<Json<'_, A>> {
+	let mut index = 0;
+	while let Some(offset) = string[index..].find(['[', '{']) {
+		let start = index + offset;
+		let mut stream = serde_json::Deserializer::from_str(&string[start..])
+			.into_iter::<serde::de::IgnoredAny>();
+		let Some(Ok(_)) = stream.next() else {
+			index = start + 1;
+			continue;
+		};
+		let end = start + stream.byte_offset();
+		let (before, json, after) = (&string[..start], &string[start..end], &string[end..]);
+		let on_own_lines = before.rsplit('\n').next().unwrap_or_default().trim().is_empty()
+			&& after.split('\n').next().unwrap_or_default().trim().is_empty();
+		if on_own_lines {
+			let value = serde_json::from_str(json).map_err(|error| {
+				io::Error::other(format!("Could not understand the JSON ({error}):\n{json}"))
+			})?;
+			return Ok(Json { value, before, json, after });
+		}
+		// Anything nested in the JSON is skipped along with it.
+		index = end;
 	}
+	Ok(Json { value: A::default(), before: string, json: "", after: "" })
 }
 
-fn get_active_plugins(wordpress_path: &str) -> OrError<Vec<String>> {
-	#[derive(Deserialize)]
+fn count_json_fields(value: &serde_json::Value) -> usize {
+	match value {
+		serde_json::Value::Array(values) => values.iter().map(count_json_fields).sum(),
+		serde_json::Value::Object(map) => {
+			map.len() + map.values().map(count_json_fields).sum::<usize>()
+		}
+		_ => 0,
+	}
+}
+
+/// Runs a `--format=json` WP-CLI command and parses its output. It fails if the command does, or if
+/// its JSON isn't as expected, but if it succeeds without printing any JSON, it's taken to have
+/// returned nothing, with a warning unless all it printed was a success message. When `verify` is set, any text discarded around
+/// the JSON is logged verbatim and the number of fields WP-CLI returned is compared with the number
+/// that were understood.
+fn wp_json<A: DeserializeOwned + Serialize + Default>(
+	runner: &dyn CommandRunner,
+	command: &mut Command,
+	verify: bool,
+	maybe_timeout: Option<Duration>,
+) -> OrError<A> {
+	let output = capture_command(runner, command, maybe_timeout)?;
+	let Json { value, before, json, after } = get_json(str::from_utf8(output.stdout.as_ref())?)
+		.map_err(|error| {
+			format!("`{}` printed unexpected output. {error}", display_command(command))
+		})?;
+	if json.is_empty() {
+		if !before.lines().all(|line| line.trim().is_empty() || line.starts_with("Success:")) {
+			warn!(
+				"`{}` printed no JSON, so it's taken to have returned nothing. It printed:\n{}",
+				display_command(command),
+				before.trim()
+			);
+		}
+		return Ok(value);
+	}
+	if verify {
+		for (position, discarded) in [("before", before), ("after", after)] {
+			if !discarded.trim().is_empty() {
+				warn!(
+					"Discarded non-JSON output {position} the JSON from `{}`:\n{discarded}",
+					display_command(command)
+				);
+			}
+		}
+		let received = serde_json::from_str(json).as_ref().map_or(0, count_json_fields);
+		let understood = count_json_fields(&serde_json::to_value(&value)?);
+		if received != understood {
+			warn!(
+				"`{}` returned {received} JSON fields but only {understood} were understood.",
+				display_command(command)
+			);
+		}
+	} else if !before.trim().is_empty() {
+		debug!("Ignored non-JSON output from WP-CLI:\n{}", before.trim());
+	}
+	Ok(value)
+}
+
+/// How to run WP-CLI.
+pub struct WpCli {
+	/// Path of the WP-CLI executable.
+	pub bin: String,
+	/// Either an alias or an `--ssh` argument selecting the installation to run WP-CLI on, if it
+	/// isn't the one at --wordpress-path.
+	pub maybe_remote: Option<String>,
+	/// Whether to run queries without loading the plugins.
+	pub skip_plugins: bool,
+	/// Whether to run queries without loading the themes.
+	pub skip_themes: bool,
+}
+
+/// Creates a WP-CLI command for the installation at `wordpress_path`, or for the one selected by
+/// `wp_cli.maybe_remote` instead if set.
+fn wp(wordpress_path: &str, wp_cli: &WpCli) -> Command {
+	let mut command = Command::new(&wp_cli.bin);
+	match wp_cli.maybe_remote {
+		Some(ref remote) => command.arg(remote),
+		None => command.arg(format!("--path={wordpress_path}")),
+	};
+	command
+}
+
+/// Creates a WP-CLI command that only reads from the installation, as `wp` does, skipping loading
+/// the plugins and themes if `wp_cli` says to. Commands that change the installation mustn't skip
+/// them, as plugins and themes may hook into the changes.
+fn wp_query(wordpress_path: &str, wp_cli: &WpCli) -> Command {
+	let mut command = wp(wordpress_path, wp_cli);
+	if wp_cli.skip_plugins {
+		command.arg("--skip-plugins");
+	}
+	if wp_cli.skip_themes {
+		command.arg("--skip-themes");
+	}
+	command
+}
+
+/// Creates a Git command for the repository at `wordpress_path`.
+fn git(git_bin: &str, wordpress_path: &str) -> Command {
+	let mut command = Command::new(git_bin);
+	command.args(["-C", wordpress_path]);
+	command
+}
+
+/// Returns the names of the active plugins. Must-use plugins and drop-ins are left out, as they
+/// can't be deactivated.
+pub fn get_active_plugins(
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	wp_cli: &WpCli,
+	verify_json: bool,
+	maybe_timeout: Option<Duration>,
+) -> OrError<Vec<String>> {
+	#[derive(Deserialize, Serialize)]
 	struct Plugin {
 		name: String,
+		#[serde(default)]
+		status: String,
 	}
-	let stdout = Command::new("wp")
-		.args([
+	let plugins: Vec<Plugin> = wp_json(
+		runner,
+		wp_query(wordpress_path, wp_cli).args([
 			"plugin",
 			"list",
-			"--fields=name",
+			"--fields=name,status",
+			"--status=active",
+			"--format=json",
+		]),
+		verify_json,
+		maybe_timeout,
+	)?;
+	Ok(plugins
+		.into_iter()
+		.filter(|plugin| !matches!(plugin.status.as_str(), "must-use" | "dropin"))
+		.map(|plugin| plugin.name)
+		.collect())
+}
+
+/// Returns the active theme along with its parent theme, if it has one.
+fn get_active_themes(
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	wp_cli: &WpCli,
+	verify_json: bool,
+	maybe_timeout: Option<Duration>,
+) -> OrError<Vec<String>> {
+	#[derive(Deserialize, Serialize)]
+	struct Theme {
+		name: String,
+		status: String,
+	}
+	let themes: Vec<Theme> = wp_json(
+		runner,
+		wp_query(wordpress_path, wp_cli).args([
+			"theme",
+			"list",
+			"--fields=name,status",
+			"--format=json",
+		]),
+		verify_json,
+		maybe_timeout,
+	)?;
+	Ok(themes
+		.into_iter()
+		.filter(|theme| theme.status == "active" || theme.status == "parent")
+		.map(|theme| theme.name)
+		.collect())
+}
+
+/// Returns the plugins each installed plugin requires through its "Requires Plugins" header,
+/// keyed by plugin name.
+fn get_plugin_dependencies(
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	wp_cli: &WpCli,
+	verify_json: bool,
+	maybe_timeout: Option<Duration>,
+) -> OrError<HashMap<String, Vec<String>>> {
+	wp_json(runner,
+		wp_query(wordpress_path, wp_cli).args([
+			"eval",
+			"require_once ABSPATH . 'wp-admin/includes/plugin.php'; $dependencies = []; foreach (get_plugins() as $file => $data) { $dependencies[dirname($file) === '.' ? basename($file, '.php') : dirname($file)] = array_values(array_filter(array_map('trim', explode(',', $data['RequiresPlugins'] ?? '')))); } echo json_encode((object) $dependencies);",
+		]),
+		verify_json,
+		maybe_timeout,
+	)
+}
+
+/// Orders the indices of `names` so that each name comes after those it depends on. Names
+/// otherwise keep their relative order, and dependency cycles are broken arbitrarily.
+fn dependency_order(names: &[&str], dependencies: &HashMap<String, Vec<String>>) -> Vec<usize> {
+	fn visit(
+		index: usize,
+		names: &[&str],
+		dependencies: &HashMap<String, Vec<String>>,
+		visited: &mut [bool],
+		order: &mut Vec<usize>,
+	) {
+		if visited[index] {
+			return;
+		}
+		visited[index] = true;
+		for dependency in dependencies.get(names[index]).into_iter().flatten() {
+			if let Some(dependency_index) = names.iter().position(|name| name == dependency) {
+				visit(dependency_index, names, dependencies, visited, order);
+			}
+		}
+		order.push(index);
+	}
+
+	let mut visited = vec![false; names.len()];
+	let mut order = Vec::with_capacity(names.len());
+	for index in 0..names.len() {
+		visit(index, names, dependencies, &mut visited, &mut order);
+	}
+	order
+}
+
+fn get_active_locales(
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	wp_cli: &WpCli,
+	verify_json: bool,
+	maybe_timeout: Option<Duration>,
+) -> OrError<Vec<String>> {
+	#[derive(Deserialize, Serialize)]
+	struct Language {
+		language: String,
+	}
+	let languages: Vec<Language> = wp_json(
+		runner,
+		wp_query(wordpress_path, wp_cli).args([
+			"language",
+			"core",
+			"list",
+			"--fields=language",
 			"--status=active",
 			"--format=json",
-			format!("--path={wordpress_path}").as_str(),
-		])
-		.output()?;
-	let stdout_str = str::from_utf8(stdout.stdout.as_ref())?;
-	let plugins: Vec<Plugin> = serde_json::from_str(get_json(stdout_str).unwrap_or("[]"))?;
-	Ok(plugins.into_iter().map(|plugin| plugin.name).collect())
-}
-
-fn stream_command(command: &mut Command) -> OrError<()> {
-	let stdout = command
-		.stdout(Stdio::piped())
-		.spawn()?
-		.stdout
-		.ok_or_else(|| io::Error::new(ErrorKind::Other, "Could not capture stdout."))?;
-	let reader = BufReader::new(stdout);
-	reader.lines().map_while(Result::ok).for_each(|line| println!("{line}"));
+		]),
+		verify_json,
+		maybe_timeout,
+	)?;
+	Ok(languages.into_iter().map(|language| language.language).collect())
+}
+
+fn display_command(command: &Command) -> String {
+	std::iter::once(command.get_program())
+		.chain(command.get_args())
+		.map(|argument| argument.to_string_lossy())
+		.collect::<Vec<_>>()
+		.join(" ")
+}
+
+/// Removes ANSI escape sequences, such as the colors WP-CLI may print, from `text`.
+pub fn strip_ansi(text: &str) -> String {
+	let mut stripped = String::with_capacity(text.len());
+	let mut characters = text.chars();
+	while let Some(character) = characters.next() {
+		if character != '\x1b' {
+			stripped.push(character);
+		} else if characters.next() == Some('[') {
+			// Skips the parameters up to and including the final byte of the control sequence.
+			characters.by_ref().find(|character| ('@'..='~').contains(character));
+		}
+	}
+	stripped
+}
+
+fn command_error(command: &Command, status: impl std::fmt::Display, stderr: &str) -> io::Error {
+	let command = display_command(command);
+	let stderr = strip_ansi(stderr);
+	let stderr = stderr.trim();
+	if stderr.is_empty() {
+		io::Error::other(format!("`{command}` failed ({status})."))
+	} else {
+		io::Error::other(format!("`{command}` failed ({status}):\n{stderr}"))
+	}
+}
+
+/// Reads all of `reader` on a new thread.
+fn read_to_end_in_thread(mut reader: impl Read + Send + 'static) -> JoinHandle<Vec<u8>> {
+	thread::spawn(move || {
+		let mut bytes = Vec::new();
+		let _ = reader.read_to_end(&mut bytes);
+		bytes
+	})
+}
+
+/// Waits for the `child` spawned from `command` to exit, killing it if it's still running after
+/// `maybe_timeout`.
+fn wait_with_timeout(
+	command: &Command,
+	child: &mut Child,
+	maybe_timeout: Option<Duration>,
+) -> OrError<ExitStatus> {
+	let Some(timeout) = maybe_timeout else {
+		return Ok(child.wait()?);
+	};
+	let deadline = Instant::now() + timeout;
+	loop {
+		if let Some(status) = child.try_wait()? {
+			return Ok(status);
+		}
+		if Instant::now() >= deadline {
+			child.kill()?;
+			child.wait()?;
+			return Err(io::Error::new(
+				io::ErrorKind::TimedOut,
+				format!(
+					"`{}` was killed as it ran for longer than {} second(s).",
+					display_command(command),
+					timeout.as_secs()
+				),
+			)
+			.into());
+		}
+		thread::sleep(Duration::from_millis(100));
+	}
+}
+
+/// Runs the commands that update WordPress and manage the Git repository, so that they can be
+/// replaced, e.g. by a fake that returns canned output and records the commands it's given.
+pub trait CommandRunner: Sync {
+	/// Runs `command`, printing its output as it's produced. Only its stderr is kept in the
+	/// returned output.
+	fn run_streaming(
+		&self,
+		command: &mut Command,
+		maybe_timeout: Option<Duration>,
+	) -> OrError<Output>;
+
+	/// Runs `command`, returning its output without printing it.
+	fn run_capture(
+		&self,
+		command: &mut Command,
+		maybe_timeout: Option<Duration>,
+	) -> OrError<Output>;
+
+	/// Runs `command` with the stdio it's been set up with, returning its exit status.
+	fn run_status(
+		&self,
+		command: &mut Command,
+		maybe_timeout: Option<Duration>,
+	) -> OrError<ExitStatus>;
+}
+
+/// File opened by `open_log_file` for --log-file, which the commands run by `ProcessRunner` and
+/// their output are written to.
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Opens the --log-file, if given, appending to it if it already exists.
+pub fn open_log_file(cli: &Cli) -> OrError<()> {
+	let Some(ref template) = cli.log_file else {
+		return Ok(());
+	};
+	let path = substitute_placeholders(
+		template,
+		&Placeholders::for_site(&cli.wordpress_path).with_time(cli)?,
+	);
+	let file = OpenOptions::new().create(true).append(true).open(&path).map_err(|error| {
+		io::Error::other(format!("Could not open the log file \"{path}\": {error}"))
+	})?;
+	*LOG_FILE.lock().unwrap_or_else(PoisonError::into_inner) = Some(file);
+	info!("Logging the commands that are run and their output to \"{path}\".");
+	Ok(())
+}
+
+/// Appends `line` to the --log-file, if it's open, prefixed with the installation being updated by
+/// the `sites` action, if any.
+fn log_to_file(line: &str) {
+	if let Some(ref mut file) = *LOG_FILE.lock().unwrap_or_else(PoisonError::into_inner) {
+		let prefix = current_site().map(|site| format!("[{site}] ")).unwrap_or_default();
+		let _ = writeln!(file, "{prefix}{line}");
+	}
+}
+
+/// Appends a header for `command` to the --log-file, if it's open.
+fn log_command_to_file(command: &Command) {
+	log_to_file(&format!(
+		"[{}] $ {}",
+		Local::now().format("%Y-%m-%d %H:%M:%S"),
+		display_command(command)
+	));
+}
+
+/// Runs commands as child processes.
+pub struct ProcessRunner;
+
+impl CommandRunner for ProcessRunner {
+	fn run_streaming(
+		&self,
+		command: &mut Command,
+		maybe_timeout: Option<Duration>,
+	) -> OrError<Output> {
+		debug!("Running `{}`.", display_command(command));
+		log_command_to_file(command);
+		let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+		let stdout =
+			child.stdout.take().ok_or_else(|| io::Error::other("Could not capture stdout."))?;
+		let stderr =
+			child.stderr.take().ok_or_else(|| io::Error::other("Could not capture stderr."))?;
+		let maybe_site = current_site();
+		let maybe_stdout_site = maybe_site.clone();
+		let stdout_thread = thread::spawn(move || {
+			set_current_site(maybe_stdout_site);
+			BufReader::new(stdout).lines().map_while(Result::ok).for_each(|line| {
+				log_to_file(&line);
+				info!("{line}");
+			});
+		});
+		let stderr_thread = thread::spawn(move || {
+			set_current_site(maybe_site);
+			BufReader::new(stderr)
+				.lines()
+				.map_while(Result::ok)
+				// Only WP-CLI's warnings are logged as such, so that --quiet hides the rest of the
+				// chatter on stderr, which is still kept for the error if the command fails.
+				.inspect(|line| {
+					log_to_file(line);
+					if line.starts_with("Warning:") {
+						warn!("{line}");
+					} else {
+						info!("{line}");
+					}
+				})
+				.collect::<Vec<_>>()
+				.join("\n")
+		});
+		// The output threads aren't joined on a timeout, as processes started by the killed one may
+		// still be holding its output open.
+		let status = wait_with_timeout(command, &mut child, maybe_timeout)?;
+		let _ = stdout_thread.join();
+		let stderr = stderr_thread.join().unwrap_or_default();
+		Ok(Output { status, stdout: Vec::new(), stderr: stderr.into_bytes() })
+	}
+
+	fn run_capture(
+		&self,
+		command: &mut Command,
+		maybe_timeout: Option<Duration>,
+	) -> OrError<Output> {
+		trace!("Running `{}`.", display_command(command));
+		log_command_to_file(command);
+		let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+		let stdout = read_to_end_in_thread(
+			child.stdout.take().ok_or_else(|| io::Error::other("Could not capture stdout."))?,
+		);
+		let stderr = read_to_end_in_thread(
+			child.stderr.take().ok_or_else(|| io::Error::other("Could not capture stderr."))?,
+		);
+		let status = wait_with_timeout(command, &mut child, maybe_timeout)?;
+		let output = Output {
+			status,
+			stdout: stdout.join().unwrap_or_default(),
+			stderr: stderr.join().unwrap_or_default(),
+		};
+		String::from_utf8_lossy(&output.stdout)
+			.lines()
+			.chain(String::from_utf8_lossy(&output.stderr).lines())
+			.for_each(log_to_file);
+		trace!(
+			"`{}` exited with {status}, printing:\n{}",
+			display_command(command),
+			String::from_utf8_lossy(&output.stdout).trim_end()
+		);
+		Ok(output)
+	}
+
+	fn run_status(
+		&self,
+		command: &mut Command,
+		maybe_timeout: Option<Duration>,
+	) -> OrError<ExitStatus> {
+		debug!("Running `{}`.", display_command(command));
+		log_command_to_file(command);
+		let mut child = command.spawn()?;
+		wait_with_timeout(command, &mut child, maybe_timeout)
+	}
+}
+
+fn capture_command(
+	runner: &dyn CommandRunner,
+	command: &mut Command,
+	maybe_timeout: Option<Duration>,
+) -> OrError<Output> {
+	let output = runner.run_capture(command, maybe_timeout)?;
+	if !output.status.success() {
+		return Err(command_error(
+			command,
+			output.status,
+			&String::from_utf8_lossy(&output.stderr),
+		)
+		.into());
+	}
+	Ok(output)
+}
+
+/// Runs `command`, printing its output as it's produced, or only what would be run if `dry_run` is
+/// set. Fails if it exits unsuccessfully.
+pub fn stream_command(
+	runner: &dyn CommandRunner,
+	command: &mut Command,
+	dry_run: bool,
+	maybe_timeout: Option<Duration>,
+) -> OrError<()> {
+	if dry_run {
+		info!("Would run `{}`.", display_command(command));
+		return Ok(());
+	}
+	let output = runner.run_streaming(command, maybe_timeout)?;
+	if !output.status.success() {
+		return Err(command_error(
+			command,
+			output.status,
+			&String::from_utf8_lossy(&output.stderr),
+		)
+		.into());
+	}
 	Ok(())
 }
 
-fn activate_plugins(wordpress_path: &str, plugins: &[String], activate: bool) -> OrError<()> {
+/// Runs the shell command `hook` with `environment` set, only logging its failure if
+/// --ignore-hook-errors is set.
+fn run_hook(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	hook: &str,
+	environment: &[(&str, &str)],
+) -> OrError<()> {
+	let mut command = if cfg!(windows) { Command::new("cmd") } else { Command::new("sh") };
+	command
+		.arg(if cfg!(windows) { "/C" } else { "-c" })
+		.arg(hook)
+		.envs(environment.iter().copied());
+	match stream_command(runner, &mut command, cli.dry_run, cli.timeout()) {
+		Err(error) if cli.ignore_hook_errors => {
+			warn!("Ignoring failed hook: {error}");
+			Ok(())
+		}
+		result => result,
+	}
+}
+
+/// Activates the `plugins` if `activate` is set, or otherwise deactivates them.
+pub fn activate_plugins(
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	wp_cli: &WpCli,
+	plugins: &[String],
+	activate: bool,
+	dry_run: bool,
+	maybe_timeout: Option<Duration>,
+) -> OrError<()> {
+	if plugins.is_empty() {
+		return Ok(());
+	}
 	let mut args = vec!["plugin", if activate { "activate" } else { "deactivate" }];
 	args.extend_from_slice(
 		plugins.iter().map(|string| string.as_str()).collect::<Vec<_>>().as_slice(),
 	);
-	let wordpress_path_argument = format!("--path={wordpress_path}");
-	args.extend_from_slice([wordpress_path_argument.as_str()].as_slice());
-	stream_command(Command::new("wp").args(args))
+	stream_command(runner, wp(wordpress_path, wp_cli).args(args), dry_run, maybe_timeout)
+}
+
+/// Restores the plugins' activation states to those from before an update, given the plugins
+/// that were `active` then.
+fn restore_plugin_states(
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	wp_cli: &WpCli,
+	active: &[String],
+	verify_json: bool,
+	dry_run: bool,
+	maybe_timeout: Option<Duration>,
+) -> OrError<()> {
+	let now_active =
+		get_active_plugins(runner, wordpress_path, wp_cli, verify_json, maybe_timeout)?;
+	let deactivated: Vec<String> =
+		active.iter().filter(|plugin| !now_active.contains(plugin)).cloned().collect();
+	let activated: Vec<String> =
+		now_active.iter().filter(|plugin| !active.contains(plugin)).cloned().collect();
+	if !deactivated.is_empty() {
+		info!("Reactivating plugins deactivated by the update: {}.", deactivated.join(", "));
+		activate_plugins(
+			runner,
+			wordpress_path,
+			wp_cli,
+			&deactivated,
+			true,
+			dry_run,
+			maybe_timeout,
+		)?;
+	}
+	if !activated.is_empty() {
+		info!("Deactivating plugins activated by the update: {}.", activated.join(", "));
+		activate_plugins(
+			runner,
+			wordpress_path,
+			wp_cli,
+			&activated,
+			false,
+			dry_run,
+			maybe_timeout,
+		)?;
+	}
+	Ok(())
+}
+
+/// Turns maintenance mode on if `active`, or otherwise off if it's on.
+fn set_maintenance_mode(
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	wp_cli: &WpCli,
+	active: bool,
+	dry_run: bool,
+	maybe_timeout: Option<Duration>,
+) -> OrError<()> {
+	if active {
+		return stream_command(
+			runner,
+			wp(wordpress_path, wp_cli).args(["maintenance-mode", "activate", "--force"]),
+			dry_run,
+			maybe_timeout,
+		);
+	}
+	// Deactivating fails if maintenance mode is already off, which updates may have done.
+	if !dry_run {
+		let mut command = wp(wordpress_path, wp_cli);
+		command.args(["maintenance-mode", "is-active"]).stdout(Stdio::null()).stderr(Stdio::null());
+		if !runner.run_status(&mut command, maybe_timeout)?.success() {
+			return Ok(());
+		}
+	}
+	stream_command(
+		runner,
+		wp(wordpress_path, wp_cli).args(["maintenance-mode", "deactivate"]),
+		dry_run,
+		maybe_timeout,
+	)
 }
 
 fn ensure_path_prefix(path: &str) -> OrError<()> {
 	if let Some(prefix) = Path::new(path).parent() {
 		fs::create_dir_all(prefix)?;
-		println!("Created path \"{}/\".", prefix.display());
+		info!("Created path \"{}/\".", prefix.display());
 	}
 	Ok(())
 }
 
-fn backup_database(wordpress_path: &str, path: &str) -> OrError<()> {
-	ensure_path_prefix(path)?;
-	stream_command(Command::new("wp").args([
-		"db",
-		"export",
-		path,
-		"--defaults",
-		format!("--path={wordpress_path}").as_str(),
-	]))
-}
-
-fn get_wordpress_version(wordpress_path: &str) -> OrError<String> {
-	Ok(String::from_utf8(
-		Command::new("wp")
-			.args(["core", "version", format!("--path={wordpress_path}").as_str()])
-			.output()?
-			.stdout,
-	)?)
-}
-
-fn remove(paths: &[String]) -> OrError<()> {
-	for path in paths {
-		if let Ok(true) = Path::new(&path).try_exists() {
-			let file_type = fs::metadata(path)?.file_type();
-			if file_type.is_dir() {
-				fs::remove_dir_all(path)?;
-			} else {
-				fs::remove_file(path)?;
-			}
-			println!("Removed \"{}\".", path);
-		}
+/// Compresses the file at `path` into `{path}.gz`, then removes the original.
+fn gzip_file(path: &str, dry_run: bool) -> OrError<()> {
+	let compressed_path = format!("{path}.gz");
+	if dry_run {
+		info!("Would compress \"{path}\" to \"{compressed_path}\".");
+		return Ok(());
 	}
+	let mut encoder = GzEncoder::new(File::create(&compressed_path)?, Compression::default());
+	io::copy(&mut File::open(path)?, &mut encoder)?;
+	encoder.finish()?;
+	fs::remove_file(path)?;
+	info!("Compressed \"{path}\" to \"{compressed_path}\".");
 	Ok(())
 }
 
-fn update(
+/// Options for the backups made by `backup_database`.
+#[derive(Default)]
+pub struct BackupOptions<'a> {
+	/// Arguments to add to `wp db export`, such as `--tables=wp_options`.
+	pub export_args: &'a [String],
+	/// Whether to gzip the backup to `{path}.gz`.
+	pub compress: bool,
+	/// Bytes to leave free on the backup's file system, if it's to be checked for enough space
+	/// first.
+	pub maybe_min_free_space: Option<u64>,
+}
+
+/// How many times the database's size a backup of it is assumed to take up at most.
+const BACKUP_SIZE_MULTIPLIER: f64 = 1.5;
+
+/// Bytes in a MiB, the unit of --min-free-space.
+const MIB: u64 = 1024 * 1024;
+
+/// Fails if the file system that `path` is on doesn't have room for a backup of the database while
+/// leaving `min_free_space` bytes free. Skipped if the free space or the database's size can't be
+/// found.
+fn check_free_space(
+	runner: &dyn CommandRunner,
 	wordpress_path: &str,
-	remove_paths: &[String],
-	maybe_backup_database_fn: Option<impl Fn() -> OrError<()>>,
-	update_fn: impl Fn() -> OrError<()>,
-	maybe_commit_fn: Option<impl Fn() -> OrError<()>>,
+	wp_cli: &WpCli,
+	path: &str,
+	min_free_space: u64,
+	maybe_timeout: Option<Duration>,
 ) -> OrError<()> {
-	if let Some(backup_database_fn) = maybe_backup_database_fn {
-		backup_database_fn()?;
-	}
-	update_fn()?;
-	let remove_paths: Vec<String> =
-		remove_paths.iter().map(|path| path.replace("{wordpress_path}", wordpress_path)).collect();
-	remove(&remove_paths)?;
-	if let Some(commit_fn) = maybe_commit_fn {
-		commit_fn()?;
+	let directory = Path::new(path).parent().filter(|parent| !parent.as_os_str().is_empty());
+	let available = match fs4::available_space(directory.unwrap_or(Path::new("."))) {
+		Ok(available) => available,
+		Err(error) => {
+			warn!("Not checking for free space for the backup, as it couldn't be found: {error}");
+			return Ok(());
+		}
+	};
+	let output = runner.run_capture(
+		wp_query(wordpress_path, wp_cli).args(["db", "size", "--size_format=b"]),
+		maybe_timeout,
+	)?;
+	let maybe_size = String::from_utf8_lossy(&output.stdout)
+		.lines()
+		.last()
+		.and_then(|line| line.trim().parse::<u64>().ok())
+		.filter(|_| output.status.success());
+	let Some(size) = maybe_size else {
+		warn!(
+			"Not checking for free space for the backup, as the database's size couldn't be found."
+		);
+		return Ok(());
+	};
+	let required = ((size as f64 * BACKUP_SIZE_MULTIPLIER) as u64).saturating_add(min_free_space);
+	if available < required {
+		let mib = |bytes: u64| bytes as f64 / MIB as f64;
+		return Err(io::Error::other(format!(
+			"Not enough free space to back up the database to \"{path}\": {:.1} MiB is available, but {:.1} MiB is needed for the {:.1} MiB database and --min-free-space.",
+			mib(available),
+			mib(required),
+			mib(size)
+		))
+		.into());
 	}
 	Ok(())
 }
 
-fn update_in_steps(
+/// Exports the database to `path`.
+pub fn backup_database(
+	runner: &dyn CommandRunner,
 	wordpress_path: &str,
-	remove_paths: &[String],
-	maybe_backup_database_fn: Option<impl Fn(&str) -> OrError<()>>,
-	exclude: &[String],
-	maybe_commit_fn: Option<impl Fn(&str, &str, &str) -> OrError<()>>,
-	subcommand: &str,
+	wp_cli: &WpCli,
+	path: &str,
+	options: &BackupOptions,
+	dry_run: bool,
+	maybe_timeout: Option<Duration>,
 ) -> OrError<()> {
-	#[derive(Deserialize)]
-	struct Update {
-		name: String,
-		version: String,
-		update_version: String,
-	}
-
-	let updates = serde_json::from_str::<Vec<Update>>(
-		get_json(str::from_utf8(
-			Command::new("wp")
-				.args([
-					subcommand,
-					"list",
-					"--update=available",
-					"--fields=name,version,update_version",
-					"--format=json",
-					format!("--path={wordpress_path}").as_str(),
-				])
-				.output()?
-				.stdout
-				.as_ref(),
-		)?)
-		.unwrap_or("[]"),
-	)?;
-	let remove_paths: Vec<String> =
-		remove_paths.iter().map(|path| path.replace("{wordpress_path}", wordpress_path)).collect();
-	for update in updates.iter().filter(|update| !exclude.contains(&update.name)) {
-		if let Some(ref backup_database_fn) = maybe_backup_database_fn {
-			backup_database_fn(update.name.as_str())?;
+	if !dry_run {
+		ensure_path_prefix(path)?;
+		if let Some(min_free_space) = options.maybe_min_free_space {
+			check_free_space(runner, wordpress_path, wp_cli, path, min_free_space, maybe_timeout)?;
 		}
-		stream_command(Command::new("wp").args([
-			subcommand,
-			"update",
-			update.name.as_str(),
-			format!("--path={wordpress_path}").as_str(),
-		]))?;
-		remove(&remove_paths)?;
-		if let Some(ref commit_fn) = maybe_commit_fn {
-			commit_fn(
-				update.name.as_str(),
-				update.version.as_str(),
-				update.update_version.as_str(),
-			)?;
+	}
+	let mut command = wp(wordpress_path, wp_cli);
+	if wp_cli.maybe_remote.is_none() {
+		stream_command(
+			runner,
+			command.args(["db", "export", path, "--defaults"]).args(options.export_args),
+			dry_run,
+			maybe_timeout,
+		)?;
+	} else {
+		// Export to stdout so that the backup is written locally even if the installation is remote.
+		command.args(["db", "export", "-", "--defaults"]).args(options.export_args);
+		if dry_run {
+			info!("Would run `{}` into \"{path}\".", display_command(&command));
+		} else {
+			// stderr is printed as it's produced, as only the exit status is returned.
+			command.stdout(File::create(path)?).stderr(Stdio::inherit());
+			let status = runner.run_status(&mut command, maybe_timeout)?;
+			if !status.success() {
+				return Err(command_error(&command, status, "").into());
+			}
+			info!("Exported the database to \"{path}\".");
 		}
 	}
+	if options.compress {
+		gzip_file(path, dry_run)?;
+	}
 	Ok(())
 }
 
-fn git_add_commit(wordpress_path: &str, message: &str) -> OrError<()> {
-	stream_command(Command::new("git").args(["-C", wordpress_path, "add", "."]))?;
-	stream_command(Command::new("git").args(["-C", wordpress_path, "commit", "-m", message]))
+/// Values to fill the placeholders of paths and branch names in with, leaving those that are
+/// `None` as they are.
+#[derive(Default)]
+struct Placeholders<'a> {
+	wordpress_path: Option<&'a str>,
+	step: Option<&'a str>,
+	unix_time: Option<u64>,
+	/// The current time, formatted with --date-format.
+	date: Option<String>,
+	name: Option<&'a str>,
+	version: Option<&'a str>,
 }
 
-fn unix_time() -> OrError<u64> {
-	Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+impl<'a> Placeholders<'a> {
+	/// Placeholders for `wordpress_path`.
+	fn for_site(wordpress_path: &'a str) -> Self {
+		Placeholders { wordpress_path: Some(wordpress_path), ..Default::default() }
+	}
+
+	/// Also fills in `{unix_time}` and `{date}` with the current time.
+	fn with_time(self, cli: &Cli) -> OrError<Self> {
+		Ok(Placeholders {
+			unix_time: Some(unix_time()?),
+			date: Some(Local::now().format(&cli.date_format).to_string()),
+			..self
+		})
+	}
 }
 
-#[derive(clap::ValueEnum, Clone)]
-pub enum Step {
-	Core,
-	Plugins,
-	Themes,
-	Translations,
+/// Replaces the `{wordpress_path}`, `{step}`, `{unix_time}`, `{date}`, `{name}` and `{version}`
+/// placeholders in `template` with the values given for them.
+fn substitute_placeholders(template: &str, placeholders: &Placeholders) -> String {
+	let unix_time = placeholders.unix_time.map(|unix_time| unix_time.to_string());
+	[
+		("{wordpress_path}", placeholders.wordpress_path),
+		("{step}", placeholders.step),
+		("{unix_time}", unix_time.as_deref()),
+		("{date}", placeholders.date.as_deref()),
+		("{name}", placeholders.name),
+		("{version}", placeholders.version),
+	]
+	.into_iter()
+	.fold(template.to_string(), |template, (placeholder, maybe_value)| match maybe_value {
+		Some(value) => template.replace(placeholder, value),
+		None => template,
+	})
 }
 
-#[derive(Parser)]
-#[command(version, about, long_about = None)]
-pub struct Cli {
-	/// A string to add to the start of commit messages.
-	#[arg(short = 'p', long)]
-	pub commit_prefix: Option<String>,
-	/// Path to use for storing database backups.
-	#[arg(short, long, default_value_t = String::from("{wordpress_path}/../{unix_time}.{step}.sql"))]
-	pub database_file_path: String,
-	/// Plugins to exclude from updates.
-	#[arg(short = 'e', long)]
-	pub exclude_plugins: Vec<String>,
-	/// Themes to exclude from updates.
-	#[arg(short = 't', long)]
-	pub exclude_themes: Vec<String>,
-	/// Disables backing-up of the database before each (sub-)step.
-	#[arg(short = 'b', long)]
-	pub no_backup_database: bool,
-	/// Disables committing after each (sub-)step.
-	#[arg(short = 'c', long)]
-	pub no_commit: bool,
-	/// String to use as a separator in commit messages.
-	#[arg(long, default_value_t = String::from(": "))]
-	pub separator: String,
-	/// The steps and order of steps taken.
-	#[arg(short, long, value_enum, default_values_t = [Step::Core, Step::Themes, Step::Plugins, Step::Translations])]
-	pub steps: Vec<Step>,
-	/// Paths to remove after each (sub-)step, before committing.
-	#[arg(short, long, default_values_t = [String::from("{wordpress_path}/$XDG_CACHE_HOME")])]
-	pub remove_paths: Vec<String>,
-	/// Path of the WordPress installation to update.
-	#[arg(short, long, default_value_t = String::from("./"))]
-	pub wordpress_path: String,
+/// Whether the file `name` matches `template`, where `{unix_time}` matches digits and `{step}`,
+/// `{date}` and `{version}` match any text.
+fn matches_backup_name(name: &str, template: &str) -> bool {
+	if let Some(rest) = template.strip_prefix("{unix_time}") {
+		let digits = name.bytes().take_while(u8::is_ascii_digit).count();
+		(1..=digits).any(|length| matches_backup_name(&name[length..], rest))
+	} else if let Some(rest) = ["{step}", "{date}", "{version}"]
+		.iter()
+		.find_map(|placeholder| template.strip_prefix(placeholder))
+	{
+		(0..=name.len())
+			.filter(|&length| name.is_char_boundary(length))
+			.any(|length| matches_backup_name(&name[length..], rest))
+	} else if let Some(character) = template.chars().next() {
+		name.starts_with(character)
+			&& matches_backup_name(&name[character.len_utf8()..], &template[character.len_utf8()..])
+	} else {
+		name.is_empty()
+	}
 }
 
-impl AsRef<Cli> for Cli {
-	fn as_ref(&self) -> &Cli {
-		self
+/// Finds the backups whose paths match `template`, newest first. Placeholders may appear in any of
+/// its components, including its directories.
+fn find_backups(template: &str) -> OrError<Vec<PathBuf>> {
+	let pattern = ["{unix_time}", "{step}", "{date}", "{version}"]
+		.iter()
+		.fold(glob::Pattern::escape(template), |pattern, placeholder| {
+			pattern.replace(placeholder, "*")
+		});
+	let template = Path::new(template);
+	let mut backups = Vec::new();
+	for path in glob::glob(&pattern)? {
+		let path = path?;
+		let matches = path.components().count() == template.components().count()
+			&& path.components().zip(template.components()).all(|(name, template)| {
+				name == template
+					|| name
+						.as_os_str()
+						.to_str()
+						.zip(template.as_os_str().to_str())
+						.is_some_and(|(name, template)| matches_backup_name(name, template))
+			});
+		if matches && path.is_file() {
+			backups.push((path.metadata()?.modified()?, path));
+		}
 	}
+	backups.sort_by_key(|(modified, _)| Reverse(*modified));
+	Ok(backups.into_iter().map(|(_, path)| path).collect())
 }
 
-fn update_core(cli: &Cli, commit_prefix: &str, wordpress_path: &str) -> OrError<()> {
-	let maybe_backup_database_fn = if cli.no_backup_database {
-		None
+/// Deletes all but the newest `keep` backups whose paths match `template`, a backup path with
+/// only its `{unix_time}`, `{date}` and `{version}` placeholders left unsubstituted. The backup at
+/// `current` is always kept.
+fn prune_backups(template: &str, current: &str, keep: usize, dry_run: bool) -> OrError<()> {
+	let backups = find_backups(template)?;
+	let old_backups = backups.into_iter().filter(|path| path != Path::new(current));
+	for path in old_backups.skip(keep.saturating_sub(1)) {
+		if dry_run {
+			info!("Would remove old backup \"{}\".", path.display());
+		} else {
+			fs::remove_file(&path)?;
+			info!("Removed old backup \"{}\".", path.display());
+		}
+	}
+	Ok(())
+}
+
+/// Arguments to `wp db export` that select the tables given by --backup-table, or leave out those
+/// given by --backup-exclude-table, with their `{prefix}` placeholders replaced by the
+/// installation's table prefix.
+fn database_export_args(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+) -> OrError<Vec<String>> {
+	let (option, tables) = if !cli.backup_tables.is_empty() {
+		("--tables", &cli.backup_tables)
+	} else if !cli.backup_exclude_tables.is_empty() {
+		("--exclude_tables", &cli.backup_exclude_tables)
 	} else {
-		Some(|| {
-			let substituted = cli.database_file_path.replace("{wordpress_path}", wordpress_path);
-			let substituted = substituted.replace("{step}", "update_core");
-			let substituted = substituted.replace("{unix_time}", unix_time()?.to_string().as_str());
-			backup_database(wordpress_path, substituted.as_ref())
-		})
-	};
-	let update_fn = || {
-		let active_plugins = get_active_plugins(wordpress_path)?;
-		activate_plugins(wordpress_path, active_plugins.as_ref(), false)?;
-		stream_command(Command::new("wp").args([
-			"core",
-			"update",
-			format!("--path={wordpress_path}").as_str(),
-		]))?;
-		activate_plugins(wordpress_path, active_plugins.as_ref(), true)
+		return Ok(Vec::new());
 	};
-	let maybe_commit_fn = if cli.no_commit {
-		None
+	let prefix = if tables.iter().any(|table| table.contains("{prefix}")) {
+		let output = capture_command(
+			runner,
+			wp_query(wordpress_path, &cli.wp_cli()).args(["db", "prefix"]),
+			cli.timeout(),
+		)?;
+		String::from_utf8(output.stdout)?.lines().last().unwrap_or_default().trim().to_string()
 	} else {
-		let version = get_wordpress_version(wordpress_path)?;
-		Some(move || {
-			git_add_commit(
-				wordpress_path,
-				format!(
-					"{commit_prefix}Update WordPress Core{0}{version} -> {1}",
-					cli.separator,
-					get_wordpress_version(wordpress_path)?
-				)
-				.as_str(),
-			)
-		})
+		String::new()
 	};
-	update(wordpress_path, &cli.remove_paths, maybe_backup_database_fn, update_fn, maybe_commit_fn)
+	let tables: Vec<String> =
+		tables.iter().map(|table| table.replace("{prefix}", &prefix)).collect();
+	Ok(vec![format!("{option}={}", tables.join(","))])
+}
+
+/// Backs up the database to the path templated by `cli.database_file_path` for `step` and the
+/// `version` being updated from, returning the path of the backup.
+fn backup_database_for_step(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	step: &str,
+	version: &str,
+) -> OrError<String> {
+	let template = substitute_placeholders(
+		&cli.database_file_path,
+		&Placeholders { step: Some(step), ..Placeholders::for_site(wordpress_path) },
+	);
+	let path = substitute_placeholders(
+		&template,
+		&Placeholders { version: Some(version), ..Default::default() }.with_time(cli)?,
+	);
+	backup_database(
+		runner,
+		wordpress_path,
+		&cli.wp_cli(),
+		&path,
+		&BackupOptions {
+			export_args: &database_export_args(cli, runner, wordpress_path)?,
+			compress: cli.compress_backups,
+			maybe_min_free_space: cli
+				.min_free_space
+				.filter(|min_free_space| *min_free_space > 0)
+				.map(|min_free_space| min_free_space * MIB),
+		},
+		cli.dry_run,
+		cli.timeout(),
+	)?;
+	let extension = if cli.compress_backups { ".gz" } else { "" };
+	let path = format!("{path}{extension}");
+	if let Some(keep) = cli.keep_backups {
+		prune_backups(format!("{template}{extension}").as_str(), &path, keep, cli.dry_run)?;
+	}
+	Ok(path)
+}
+
+/// Backups made before a (sub-)step, to roll it back with.
+#[derive(Default)]
+struct Backup {
+	/// Path of the database backup.
+	maybe_database: Option<String>,
+	/// Path of the files backed up, relative to the installation, and of the archive of them.
+	maybe_files: Option<(PathBuf, String)>,
 }
 
-fn update_plugins(cli: &Cli, commit_prefix: &str, wordpress_path: &str) -> OrError<()> {
-	let maybe_backup_database_fn = if cli.no_backup_database {
+/// Backs up the database unless `cli.no_backup_database` is set, and the files at `files_path` in
+/// the installation if `cli.backup_files` is set, for `step` and the `version` being updated from.
+fn backup_for_step(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	step: &str,
+	version: &str,
+	files_path: &Path,
+) -> OrError<Backup> {
+	let maybe_database = if cli.no_backup_database {
 		None
 	} else {
-		Some(|name: &_| {
-			let substituted = cli.database_file_path.replace("{wordpress_path}", wordpress_path);
-			let substituted =
-				substituted.replace("{step}", format!("update_plugin.{name}").as_str());
-			let substituted = substituted.replace("{unix_time}", unix_time()?.to_string().as_str());
-			backup_database(wordpress_path, substituted.as_ref())
-		})
+		Some(backup_database_for_step(cli, runner, wordpress_path, step, version)?)
 	};
-	let maybe_commit_fn = if cli.no_commit {
-		None
+	let maybe_files = if cli.backup_files {
+		backup_files_for_step(cli, wordpress_path, step, version, files_path)?
+			.map(|archive| (files_path.to_path_buf(), archive))
 	} else {
-		Some(|name: &_, version: &_, update_version: &_| {
-			git_add_commit(
-				wordpress_path,
-				format!(
-					"{commit_prefix}Update plugin{0}{name}{0}{version} -> {update_version}",
-					cli.separator
-				)
-				.as_str(),
-			)
-		})
+		None
 	};
-	update_in_steps(
-		wordpress_path,
-		&cli.remove_paths,
-		maybe_backup_database_fn,
-		&cli.exclude_plugins,
-		maybe_commit_fn,
-		"plugin",
-	)
+	Ok(Backup { maybe_database, maybe_files })
 }
 
-fn update_themes(cli: &Cli, commit_prefix: &str, wordpress_path: &str) -> OrError<()> {
-	let maybe_backup_database_fn = if cli.no_backup_database {
+/// Archives the files at `files_path` in the installation to the path templated by
+/// `cli.database_file_path` for `step` and `version`, with its ".sql" extension replaced by ".tar",
+/// returning the path of the archive, or `None` if there are no files to back up.
+fn backup_files_for_step(
+	cli: &Cli,
+	wordpress_path: &str,
+	step: &str,
+	version: &str,
+	files_path: &Path,
+) -> OrError<Option<String>> {
+	if fs::symlink_metadata(Path::new(wordpress_path).join(files_path)).is_err() {
+		info!("Not backing up \"{}\" as it doesn't exist.", files_path.display());
+		return Ok(None);
+	}
+	let template = substitute_placeholders(
+		&cli.database_file_path,
+		&Placeholders { step: Some(step), ..Placeholders::for_site(wordpress_path) },
+	);
+	let extension = if cli.compress_backups { ".tar.gz" } else { ".tar" };
+	let template = format!("{}{extension}", template.strip_suffix(".sql").unwrap_or(&template));
+	let path = substitute_placeholders(
+		&template,
+		&Placeholders { version: Some(version), ..Default::default() }.with_time(cli)?,
+	);
+	if !cli.dry_run {
+		ensure_path_prefix(&path)?;
+	}
+	archive_files(wordpress_path, files_path, &path, cli.compress_backups, cli.dry_run)?;
+	if let Some(keep) = cli.keep_backups {
+		prune_backups(&template, &path, keep, cli.dry_run)?;
+	}
+	Ok(Some(path))
+}
+
+/// Path of the files of the plugin or theme `name` relative to the installation, which is a single
+/// PHP file for some plugins.
+fn item_files_path(wordpress_path: &str, subcommand: &str, name: &str) -> PathBuf {
+	let directory = Path::new("wp-content").join(format!("{subcommand}s"));
+	let file = directory.join(format!("{name}.php"));
+	if Path::new(wordpress_path).join(&file).is_file() {
+		file
+	} else {
+		directory.join(name)
+	}
+}
+
+/// Archives the file or directory at `files_path` in the installation to a tarball at
+/// `archive_path`, gzipping it if `compress` is set.
+fn archive_files(
+	wordpress_path: &str,
+	files_path: &Path,
+	archive_path: &str,
+	compress: bool,
+	dry_run: bool,
+) -> OrError<()> {
+	fn append<W: Write>(writer: W, wordpress_path: &str, files_path: &Path) -> io::Result<W> {
+		let mut builder = tar::Builder::new(writer);
+		builder.follow_symlinks(false);
+		let full_path = Path::new(wordpress_path).join(files_path);
+		if full_path.is_dir() {
+			builder.append_dir_all(files_path, &full_path)?;
+		} else {
+			builder.append_path_with_name(&full_path, files_path)?;
+		}
+		builder.into_inner()
+	}
+
+	if dry_run {
+		info!("Would archive \"{}\" to \"{archive_path}\".", files_path.display());
+		return Ok(());
+	}
+	let file = File::create(archive_path)?;
+	if compress {
+		append(GzEncoder::new(file, Compression::default()), wordpress_path, files_path)?
+			.finish()?;
+	} else {
+		append(file, wordpress_path, files_path)?;
+	}
+	info!("Archived \"{}\" to \"{archive_path}\".", files_path.display());
+	Ok(())
+}
+
+/// Replaces the files at `files_path` in the installation with those in the tarball at
+/// `archive_path`, decompressing it if it's gzipped.
+fn restore_files(
+	wordpress_path: &str,
+	files_path: &Path,
+	archive_path: &str,
+	dry_run: bool,
+) -> OrError<()> {
+	let full_path = Path::new(wordpress_path).join(files_path);
+	if dry_run {
+		info!("Would replace \"{}\" with \"{archive_path}\".", full_path.display());
+		return Ok(());
+	}
+	match fs::symlink_metadata(&full_path) {
+		Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&full_path)?,
+		Ok(_) => fs::remove_file(&full_path)?,
+		Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+		Err(error) => return Err(error.into()),
+	}
+	let file = File::open(archive_path)?;
+	if archive_path.ends_with(".gz") {
+		tar::Archive::new(GzDecoder::new(file)).unpack(wordpress_path)?;
+	} else {
+		tar::Archive::new(file).unpack(wordpress_path)?;
+	}
+	Ok(())
+}
+
+/// Imports the database backup at `path`, decompressing it first if it's gzipped.
+fn import_database(
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	wp_cli: &WpCli,
+	path: &Path,
+	dry_run: bool,
+	maybe_timeout: Option<Duration>,
+) -> OrError<()> {
+	let decompressed_path = if path.extension().is_some_and(|extension| extension == "gz") {
+		let decompressed_path =
+			env::temp_dir().join(format!("update-wp-restore-{}.sql", std::process::id()));
+		if dry_run {
+			info!(
+				"Would decompress \"{}\" to \"{}\".",
+				path.display(),
+				decompressed_path.display()
+			);
+		} else {
+			io::copy(
+				&mut GzDecoder::new(File::open(path)?),
+				&mut File::create(&decompressed_path)?,
+			)?;
+		}
+		Some(decompressed_path)
+	} else {
+		None
+	};
+	let path = decompressed_path.as_deref().unwrap_or(path);
+	let mut command = wp(wordpress_path, wp_cli);
+	command.args(["db", "import"]);
+	if wp_cli.maybe_remote.is_none() {
+		command.arg(path);
+	} else {
+		// Import from stdin, as the backup is only on the local host, which may not be the
+		// installation's.
+		command.arg("-");
+		if !dry_run {
+			command.stdin(File::open(path)?);
+		}
+	}
+	let result = stream_command(runner, &mut command, dry_run, maybe_timeout);
+	if let (Some(decompressed_path), false) = (decompressed_path, dry_run) {
+		fs::remove_file(decompressed_path)?;
+	}
+	result
+}
+
+/// Restores the database and files from `backup` if `cli.rollback_on_failure` is set, after an
+/// update failed with `error`. Returns the error to abort with.
+fn roll_back(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	backup: &Backup,
+	error: Box<dyn Error>,
+) -> Box<dyn Error> {
+	if !cli.rollback_on_failure {
+		return error;
+	}
+	let mut rollback_errors = Vec::new();
+	match backup.maybe_database {
+		Some(ref database) => {
+			warn!("Rolling back the database using \"{database}\" as the update failed.");
+			match import_database(
+				runner,
+				wordpress_path,
+				&cli.wp_cli(),
+				Path::new(database),
+				cli.dry_run,
+				cli.timeout(),
+			) {
+				Ok(()) => warn!("Rolled back the database using \"{database}\"."),
+				Err(rollback_error) => rollback_errors.push(format!(
+					"Rolling back the database using \"{database}\" also failed: {rollback_error}"
+				)),
+			}
+		}
+		None => warn!("Not rolling back the database as no backup was made."),
+	}
+	if let Some((ref files_path, ref archive)) = backup.maybe_files {
+		let files_path_display = files_path.display();
+		warn!("Rolling back \"{files_path_display}\" using \"{archive}\" as the update failed.");
+		match restore_files(wordpress_path, files_path, archive, cli.dry_run) {
+			Ok(()) => warn!("Rolled back \"{files_path_display}\" using \"{archive}\"."),
+			Err(rollback_error) => rollback_errors.push(format!(
+				"Rolling back \"{files_path_display}\" using \"{archive}\" also failed: {rollback_error}"
+			)),
+		}
+	}
+	if rollback_errors.is_empty() {
+		error
+	} else {
+		io::Error::other(format!("{error}\n{}", rollback_errors.join("\n"))).into()
+	}
+}
+
+/// Finds a version token like `6.4` or `6.4.1` in `line`.
+fn find_version(line: &str) -> Option<&str> {
+	let is_numeric =
+		|part: &str| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit());
+	line.split(|character: char| !character.is_ascii_digit() && character != '.')
+		.map(|candidate| candidate.trim_matches('.'))
+		.find_map(|candidate| {
+			let parts: Vec<_> = candidate.split('.').collect();
+			(parts.len() >= 2 && parts.iter().all(|part| is_numeric(part))).then(|| {
+				&candidate[..parts.iter().take(3).map(|part| part.len() + 1).sum::<usize>() - 1]
+			})
+		})
+}
+
+/// Extracts the version from `wp core version` output, ignoring the trailing newline and any
+/// PHP notices printed before it.
+fn parse_wordpress_version(output: &str) -> Option<&str> {
+	output.lines().rev().find_map(find_version)
+}
+
+/// Returns the installed version of WordPress core.
+pub fn get_wordpress_version(
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	wp_cli: &WpCli,
+	maybe_timeout: Option<Duration>,
+) -> OrError<String> {
+	let output = String::from_utf8(
+		capture_command(
+			runner,
+			wp_query(wordpress_path, wp_cli).args(["core", "version"]),
+			maybe_timeout,
+		)?
+		.stdout,
+	)?;
+	parse_wordpress_version(&output).map(String::from).ok_or_else(|| {
+		io::Error::other(format!("Could not find a version in `wp core version` output: {output}"))
+			.into()
+	})
+}
+
+/// Removes every path matching each of the glob `patterns`. Patterns that aren't valid globs are
+/// treated as literal paths. Unless `allow_outside` is set, paths that don't resolve to somewhere
+/// inside `wordpress_path` are refused.
+fn remove(
+	patterns: &[String],
+	wordpress_path: &str,
+	allow_outside: bool,
+	dry_run: bool,
+) -> OrError<()> {
+	let wordpress_path = fs::canonicalize(wordpress_path)?;
+	for pattern in patterns {
+		let paths = match glob::glob(pattern) {
+			Ok(paths) => paths.collect::<Result<Vec<_>, _>>()?,
+			Err(_) => vec![PathBuf::from(pattern)],
+		};
+		for path in paths {
+			if let Ok(true) = path.try_exists() {
+				let canonical_path = fs::canonicalize(&path)?;
+				if !allow_outside
+					&& (canonical_path == wordpress_path
+						|| !canonical_path.starts_with(&wordpress_path))
+				{
+					return Err(io::Error::other(format!(
+						"Refusing to remove \"{}\" as it resolves to \"{}\", which is outside \"{}\". Use --allow-remove-outside to remove it anyway.",
+						path.display(),
+						canonical_path.display(),
+						wordpress_path.display()
+					))
+					.into());
+				}
+				if dry_run {
+					info!("Would remove \"{}\".", path.display());
+					continue;
+				}
+				let file_type = fs::metadata(&path)?.file_type();
+				if file_type.is_dir() {
+					fs::remove_dir_all(&path)?;
+				} else {
+					fs::remove_file(&path)?;
+				}
+				info!("Removed \"{}\".", path.display());
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Expands `$VAR` and `${VAR}` references to environment variables. Undefined variables expand
+/// to an empty string.
+fn expand_env_vars(string: &str) -> String {
+	let mut expanded = String::with_capacity(string.len());
+	let mut rest = string;
+	while let Some(index) = rest.find('$') {
+		expanded.push_str(&rest[..index]);
+		rest = &rest[index + 1..];
+		let (name, remainder) = if let Some(braced) = rest.strip_prefix('{') {
+			match braced.split_once('}') {
+				Some((name, remainder)) => (name, remainder),
+				None => ("", rest),
+			}
+		} else {
+			let end = rest
+				.find(|character: char| !character.is_ascii_alphanumeric() && character != '_')
+				.unwrap_or(rest.len());
+			(&rest[..end], &rest[end..])
+		};
+		if name.is_empty() {
+			expanded.push('$');
+			continue;
+		}
+		match env::var(name) {
+			Ok(value) => expanded.push_str(&value),
+			Err(_) => {
+				warn!("Environment variable \"{name}\" is not set, expanding it to \"\".")
+			}
+		}
+		rest = remainder;
+	}
+	expanded.push_str(rest);
+	expanded
+}
+
+/// Default --remove-paths, which removes the directory literally named "$XDG_CACHE_HOME" that
+/// WP-CLI can leave in the installation.
+const DEFAULT_REMOVE_PATH: &str = "{wordpress_path}/$XDG_CACHE_HOME";
+
+/// Substitutes the placeholders and expands the environment variables in the --remove-paths. The
+/// default keeps its "$XDG_CACHE_HOME" literal, also removing the cache in the installation if
+/// `XDG_CACHE_HOME` is a relative path.
+fn resolve_remove_paths(remove_paths: &[String], wordpress_path: &str) -> Vec<String> {
+	remove_paths
+		.iter()
+		.flat_map(|path| {
+			let substituted =
+				substitute_placeholders(path, &Placeholders::for_site(wordpress_path));
+			if path != DEFAULT_REMOVE_PATH {
+				return vec![expand_env_vars(&substituted)];
+			}
+			let maybe_cache = env::var("XDG_CACHE_HOME")
+				.ok()
+				.filter(|cache| !cache.is_empty() && Path::new(cache).is_relative())
+				.map(|cache| Path::new(wordpress_path).join(cache).to_string_lossy().into_owned());
+			std::iter::once(substituted).chain(maybe_cache).collect()
+		})
+		.filter(|path| {
+			// An unset variable can collapse a path down to the installation itself.
+			let is_wordpress_path =
+				Path::new(path).components().eq(Path::new(wordpress_path).components());
+			if is_wordpress_path {
+				warn!("Not removing \"{path}\" as it is the WordPress installation itself.");
+			}
+			!is_wordpress_path
+		})
+		.collect()
+}
+
+/// Set once SIGINT or SIGTERM is received, so the run stops at the next update and cleans up.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Handles SIGINT and SIGTERM by stopping the run before the next update, which cleans up as if it
+/// failed. Receiving either again exits immediately.
+pub fn handle_interrupts() -> OrError<()> {
+	ctrlc::set_handler(|| {
+		if INTERRUPTED.swap(true, Ordering::SeqCst) {
+			std::process::exit(130);
+		}
+		warn!("Interrupted, so stopping after cleaning up. Interrupt again to exit immediately.");
+	})?;
+	Ok(())
+}
+
+/// Fails if the run was interrupted.
+fn check_interrupted() -> OrError<()> {
+	if INTERRUPTED.load(Ordering::SeqCst) {
+		return Err(io::Error::other("Interrupted.").into());
+	}
+	Ok(())
+}
+
+thread_local! {
+	/// Installation being updated by the `sites` action on this thread, which logs are prefixed
+	/// with.
+	static CURRENT_SITE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// The installation being updated by the `sites` action on this thread, if any.
+pub fn current_site() -> Option<String> {
+	CURRENT_SITE.with_borrow(Clone::clone)
+}
+
+fn set_current_site(maybe_site: Option<String>) {
+	CURRENT_SITE.set(maybe_site);
+}
+
+/// Set once every update is confirmed at once with --interactive.
+static CONFIRMED_ALL: AtomicBool = AtomicBool::new(false);
+
+/// Asks whether to apply `change` if `cli.interactive` is set, returning `None` if the rest of the
+/// step should be skipped. Confirms without asking when stdin isn't a terminal or every update was
+/// confirmed.
+fn confirm(cli: &Cli, change: &str) -> OrError<Option<bool>> {
+	if !cli.interactive || CONFIRMED_ALL.load(Ordering::SeqCst) || !io::stdin().is_terminal() {
+		return Ok(Some(true));
+	}
+	loop {
+		print!("{change}? [y/N/a(ll)/s(kip rest)] ");
+		io::stdout().flush()?;
+		let mut answer = String::new();
+		io::stdin().read_line(&mut answer)?;
+		match answer.trim().to_lowercase().as_str() {
+			"y" | "yes" => return Ok(Some(true)),
+			"" | "n" | "no" => return Ok(Some(false)),
+			"a" | "all" => {
+				CONFIRMED_ALL.store(true, Ordering::SeqCst);
+				return Ok(Some(true));
+			}
+			"s" | "skip" => return Ok(None),
+			_ => println!("Please answer y, n, a or s."),
+		}
+	}
+}
+
+/// Runs `f`, running it again up to --retries times while it fails, waiting twice as long before
+/// each retry as before the last.
+fn retry<A>(cli: &Cli, f: impl Fn() -> OrError<A>) -> OrError<A> {
+	let mut attempt = 1;
+	let mut delay = cli.retry_delay;
+	loop {
+		match f() {
+			Err(_) if attempt <= cli.retries && !INTERRUPTED.load(Ordering::SeqCst) => {
+				attempt += 1;
+				warn!(
+					"Failed, retrying in {delay} second(s) (attempt {attempt} of {}).",
+					cli.retries + 1
+				);
+				thread::sleep(Duration::from_secs(delay));
+				delay = delay.saturating_mul(2);
+			}
+			result => return result,
+		}
+	}
+}
+
+fn update(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	item: &mut ItemReport,
+	maybe_backup_fn: Option<impl Fn() -> OrError<Backup>>,
+	update_fn: impl Fn() -> OrError<()>,
+	maybe_commit_fn: Option<impl Fn() -> OrError<()>>,
+) -> OrError<()> {
+	write_event(cli, item_event("item_start", item));
+	let backup = match maybe_backup_fn {
+		Some(backup_fn) => backup_fn()?,
+		None => Backup::default(),
+	};
+	item.backup = backup.maybe_database.clone();
+	item.files_backup = backup.maybe_files.as_ref().map(|(_, archive)| archive.clone());
+	if item.backup.is_some() || item.files_backup.is_some() {
+		write_event(cli, item_event("backup_done", item));
+	}
+	update_fn()
+		.and_then(|()| smoke_test(cli, runner, wordpress_path))
+		.map_err(|error| roll_back(cli, runner, wordpress_path, &backup, error))?;
+	write_event(cli, item_event("update_done", item));
+	remove(
+		&resolve_remove_paths(&cli.remove_paths, wordpress_path),
+		wordpress_path,
+		cli.allow_remove_outside,
+		cli.dry_run,
+	)?;
+	if let Some(commit_fn) = maybe_commit_fn {
+		commit_fn()?;
+		if !cli.dry_run {
+			item.commit_sha = git_head(runner, &cli.git_bin, wordpress_path).ok();
+		}
+		write_event(cli, item_event("commit_done", item));
+	}
+	Ok(())
+}
+
+/// An available update of WordPress core.
+#[derive(Deserialize, Serialize)]
+struct CoreUpdate {
+	version: String,
+}
+
+/// Returns the available updates of WordPress core, newest first.
+fn get_core_updates(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+) -> OrError<Vec<CoreUpdate>> {
+	wp_json(
+		runner,
+		wp_query(wordpress_path, &cli.wp_cli()).args(["core", "check-update", "--format=json"]),
+		cli.verify_wp_cli_json_output,
+		cli.timeout(),
+	)
+}
+
+/// Whether any translations of core, plugins or themes can be updated.
+fn has_translation_updates(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+) -> OrError<bool> {
+	let updates: Vec<serde_json::Value> = wp_json(
+		runner,
+		wp_query(wordpress_path, &cli.wp_cli())
+			.args(["eval", "echo json_encode(wp_get_translation_updates());"]),
+		cli.verify_wp_cli_json_output,
+		cli.timeout(),
+	)?;
+	Ok(updates.iter().any(|update| {
+		let language = update["language"].as_str().unwrap_or_default();
+		cli.locales.is_empty() || cli.locales.iter().any(|locale| locale == language)
+	}))
+}
+
+/// An available update of a plugin or theme.
+#[derive(Deserialize, Serialize)]
+pub struct Update {
+	pub name: String,
+	pub version: String,
+	pub update_version: String,
+	/// URL of the update's package, which is `None` if WP-CLI doesn't know it.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub update_package: Option<String>,
+	/// Whether the item is reinstalled at its installed version instead, as by --reinstall-plugin.
+	#[serde(skip)]
+	pub reinstall: bool,
+}
+
+impl Update {
+	/// Whether the update is served from WordPress.org.
+	pub fn is_from_wordpress_org(&self) -> bool {
+		self.update_package
+			.as_deref()
+			.is_some_and(|url| url.starts_with("https://downloads.wordpress.org/"))
+	}
+}
+
+/// Whether updating from `version` to `update_version` increases the major version, which is the
+/// first component of a semantic version, or `None` if either isn't one.
+fn is_major_bump(version: &str, update_version: &str) -> Option<bool> {
+	let major = |version: &str| -> Option<u64> {
+		let mut components = version.split(['-', '+']).next()?.split('.');
+		let major = components.next()?.parse().ok()?;
+		components.all(|component| component.parse::<u64>().is_ok()).then_some(major)
+	};
+	Some(major(update_version)? > major(version)?)
+}
+
+/// Returns all the available updates of the plugins or themes, depending on `subcommand`.
+pub fn fetch_updates(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	subcommand: &str,
+) -> OrError<Vec<Update>> {
+	wp_json(
+		runner,
+		wp_query(wordpress_path, &cli.wp_cli()).args([
+			subcommand,
+			"list",
+			"--update=available",
+			"--fields=name,version,update_version,update_package",
+			"--format=json",
+		]),
+		cli.verify_wp_cli_json_output,
+		cli.timeout(),
+	)
+}
+
+/// Returns the `updates` of the plugins or themes, depending on `subcommand`, that are selected for
+/// updating.
+fn select_updates(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	subcommand: &str,
+	updates: Vec<Update>,
+) -> OrError<Vec<Update>> {
+	let maybe_include = if subcommand == "theme" && cli.active_themes_only {
+		Some(get_active_themes(
+			runner,
+			wordpress_path,
+			&cli.wp_cli(),
+			cli.verify_wp_cli_json_output,
+			cli.timeout(),
+		)?)
+	} else {
+		None
+	};
+	let (only, exclude) = if subcommand == "plugin" {
+		(&cli.only_plugins, &cli.exclude_plugins)
+	} else {
+		(&cli.only_themes, &cli.exclude_themes)
+	};
+	Ok(updates
+		.into_iter()
+		.filter(|update| {
+			let included = (only.is_empty() || only.contains(&update.name))
+				&& maybe_include.as_ref().is_none_or(|include| include.contains(&update.name));
+			if !included {
+				info!(
+					"Skipping {subcommand} \"{}\" as it isn't selected for updating.",
+					update.name
+				);
+			}
+			let excluded = exclude.contains(&update.name);
+			if excluded && only.contains(&update.name) {
+				info!(
+					"Skipping {subcommand} \"{0}\" as it's excluded by --exclude-{subcommand}s, even though it's selected by --only-{subcommand}s.",
+					update.name
+				);
+			}
+			if !included || excluded {
+				return false;
+			}
+			if cli.skip_external && !update.is_from_wordpress_org() {
+				info!(
+					"Skipping {subcommand} \"{}\" due to --skip-external, as its update isn't from WordPress.org.",
+					update.name
+				);
+				return false;
+			}
+			if subcommand != "plugin" || !cli.plugin_minor_only.contains(&update.name) {
+				return true;
+			}
+			match is_major_bump(&update.version, &update.update_version) {
+				Some(true) => {
+					info!(
+						"Holding back the major update of plugin \"{}\" from {} to {} due to --plugin-minor-only.",
+						update.name, update.version, update.update_version
+					);
+					false
+				}
+				Some(false) => true,
+				None => {
+					warn!(
+						"Updating plugin \"{}\" from {} to {} despite --plugin-minor-only, as these aren't semantic versions.",
+						update.name, update.version, update.update_version
+					);
+					true
+				}
+			}
+		})
+		.filter(|update| is_old_enough(cli, subcommand, update))
+		.collect())
+}
+
+/// Whether `update` was released at least --min-age-days ago, if it's of a plugin. Updates whose
+/// release dates aren't known are updated, with a warning.
+#[cfg(feature = "min-age")]
+fn is_old_enough(cli: &Cli, subcommand: &str, update: &Update) -> bool {
+	let (Some(min_age_days), "plugin") = (cli.min_age_days, subcommand) else {
+		return true;
+	};
+	match get_release_time(cli, update) {
+		Ok(released)
+			if Utc::now().naive_utc() - released < TimeDelta::days(min_age_days.into()) =>
+		{
+			info!(
+				"Holding back the update of plugin \"{}\" to {}, released at {released} UTC, due to --min-age-days.",
+				update.name, update.update_version
+			);
+			false
+		}
+		Ok(_) => true,
+		Err(error) => {
+			warn!(
+				"Updating plugin \"{}\" to {} despite --min-age-days, as its release date isn't known: {error}",
+				update.name, update.update_version
+			);
+			true
+		}
+	}
+}
+
+#[cfg(not(feature = "min-age"))]
+fn is_old_enough(_cli: &Cli, _subcommand: &str, _update: &Update) -> bool {
+	true
+}
+
+/// Returns when the plugin update `update` was released, in UTC, according to the WordPress.org
+/// plugin API, which only gives the time of the plugin's latest release.
+#[cfg(feature = "min-age")]
+fn get_release_time(cli: &Cli, update: &Update) -> OrError<NaiveDateTime> {
+	#[derive(Deserialize)]
+	struct PluginInformation {
+		version: String,
+		last_updated: String,
+	}
+	if !update.is_from_wordpress_org() {
+		return Err(io::Error::other("its update isn't from WordPress.org").into());
+	}
+	let mut agent = ureq::AgentBuilder::new();
+	if let Some(timeout) = cli.timeout() {
+		agent = agent.timeout(timeout);
+	}
+	let response = agent
+		.build()
+		.get("https://api.wordpress.org/plugins/info/1.2/")
+		.query("action", "plugin_information")
+		.query("request[slug]", &update.name)
+		.call()?
+		.into_string()?;
+	let information: PluginInformation = serde_json::from_str(&response)?;
+	if information.version != update.update_version {
+		return Err(io::Error::other(format!(
+			"its latest release on WordPress.org is {}",
+			information.version
+		))
+		.into());
+	}
+	// As in "2024-05-01 3:45pm GMT".
+	Ok(NaiveDateTime::parse_from_str(&information.last_updated, "%Y-%m-%d %I:%M%P GMT")?)
+}
+
+fn update_in_steps(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	maybe_backup_fn: Option<impl Fn(&str, &str) -> OrError<Backup>>,
+	maybe_commit_fn: Option<impl Fn(&Update, Option<&Path>) -> OrError<()>>,
+	subcommand: &str,
+	items: &mut Vec<ItemReport>,
+) -> OrError<()> {
+	let updates = fetch_updates(cli, runner, wordpress_path, subcommand)?;
+	let names_with_updates: Vec<String> =
+		updates.iter().map(|update| update.name.clone()).collect();
+	let mut updates = select_updates(cli, runner, wordpress_path, subcommand, updates)?;
+	if subcommand == "plugin" {
+		for name in &cli.reinstall_plugins {
+			if updates.iter().any(|update| &update.name == name) {
+				info!(
+					"Updating plugin \"{name}\" instead of reinstalling it, as it has an update."
+				);
+				continue;
+			}
+			if names_with_updates.contains(name) {
+				info!(
+					"Reinstalling plugin \"{name}\" at its installed version, as its update isn't selected."
+				);
+			}
+			let version = get_item_version(
+				runner,
+				wordpress_path,
+				&cli.wp_cli(),
+				"plugin",
+				name,
+				cli.timeout(),
+			)?;
+			updates.push(Update {
+				name: name.clone(),
+				version: version.clone(),
+				update_version: version,
+				update_package: None,
+				reinstall: true,
+			});
+		}
+	}
+	let remove_paths = resolve_remove_paths(&cli.remove_paths, wordpress_path);
+	let mut updates: Vec<&Update> = updates.iter().collect();
+	let maybe_dependencies = if subcommand == "plugin" && cli.resolve_plugin_dependencies {
+		let dependencies = get_plugin_dependencies(
+			runner,
+			wordpress_path,
+			&cli.wp_cli(),
+			cli.verify_wp_cli_json_output,
+			cli.timeout(),
+		)?;
+		let names: Vec<&str> = updates.iter().map(|update| update.name.as_str()).collect();
+		let order = dependency_order(&names, &dependencies);
+		updates = order.into_iter().map(|index| updates[index]).collect();
+		Some(dependencies)
+	} else {
+		None
+	};
+	// Each update is prepared by running its hook and backing up the database, then updated, then
+	// finished by restoring plugin states, removing paths, committing and running its hook. With
+	// --jobs, the updates are run in parallel between preparing and finishing all of them.
+	struct Pending {
+		item: ItemReport,
+		stage: &'static str,
+		backup: Backup,
+		maybe_active_plugins: Option<Vec<String>>,
+		maybe_path: Option<PathBuf>,
+		result: OrError<()>,
+		/// Time spent on the update so far, excluding any waiting for other updates.
+		elapsed: Duration,
+	}
+
+	fn item_environment<'a>(
+		subcommand: &'a str,
+		wordpress_path: &'a str,
+		update: &'a Update,
+	) -> [(&'a str, &'a str); 5] {
+		[
+			("UPDATEWP_STEP", subcommand),
+			("UPDATEWP_WORDPRESS_PATH", wordpress_path),
+			("UPDATEWP_ITEM_NAME", update.name.as_str()),
+			("UPDATEWP_ITEM_VERSION", update.version.as_str()),
+			("UPDATEWP_ITEM_UPDATE_VERSION", update.update_version.as_str()),
+		]
+	}
+
+	let total = updates.len();
+	// Numbered from 1 out of `total`, to show the progress through the step.
+	let prepare = |number: usize, update: &Update| {
+		if let Some(ref dependencies) = maybe_dependencies {
+			for dependency in dependencies.get(&update.name).into_iter().flatten() {
+				if !dependencies.contains_key(dependency) {
+					warn!(
+						"Plugin \"{}\" requires \"{dependency}\", which isn't installed.",
+						update.name
+					);
+				}
+			}
+		}
+		if update.reinstall {
+			info!(
+				"[{number}/{total}] Reinstalling {subcommand} \"{}\" at {}.",
+				update.name, update.version
+			);
+		} else {
+			info!(
+				"[{number}/{total}] Updating {subcommand} \"{}\" from {} to {}.",
+				update.name, update.version, update.update_version
+			);
+		}
+		let start = Instant::now();
+		let mut pending = Pending {
+			item: ItemReport {
+				step: format!("{subcommand}s"),
+				name: Some(update.name.clone()),
+				from_version: Some(update.version.clone()),
+				to_version: Some(update.update_version.clone()),
+				..Default::default()
+			},
+			stage: "pre-item hook",
+			backup: Backup::default(),
+			maybe_active_plugins: None,
+			maybe_path: None,
+			result: Ok(()),
+			elapsed: Duration::ZERO,
+		};
+		write_event(cli, item_event("item_start", &pending.item));
+		pending.result = (|| {
+			check_interrupted()?;
+			if let Some(ref hook) = cli.pre_item_hook {
+				run_hook(cli, runner, hook, &item_environment(subcommand, wordpress_path, update))?;
+			}
+			pending.stage = "backup";
+			if let Some(ref backup_fn) = maybe_backup_fn {
+				pending.backup = backup_fn(update.name.as_str(), update.version.as_str())?;
+			}
+			pending.item.backup = pending.backup.maybe_database.clone();
+			pending.item.files_backup =
+				pending.backup.maybe_files.as_ref().map(|(_, archive)| archive.clone());
+			if pending.item.backup.is_some() || pending.item.files_backup.is_some() {
+				write_event(cli, item_event("backup_done", &pending.item));
+			}
+			pending.stage = "update";
+			if subcommand == "plugin" && cli.preserve_plugin_state {
+				pending.maybe_active_plugins = Some(get_active_plugins(
+					runner,
+					wordpress_path,
+					&cli.wp_cli(),
+					cli.verify_wp_cli_json_output,
+					cli.timeout(),
+				)?);
+			}
+			// Updates in parallel are committed separately by only staging their own files.
+			if cli.jobs > 1 && maybe_commit_fn.is_some() {
+				pending.maybe_path = Some(get_item_path(
+					runner,
+					wordpress_path,
+					&cli.wp_cli(),
+					subcommand,
+					update.name.as_str(),
+					cli.timeout(),
+				)?);
+			}
+			Ok(())
+		})();
+		pending.elapsed = start.elapsed();
+		pending
+	};
+	let run_update = |update: &Update| {
+		let name = update.name.as_str();
+		check_interrupted()?;
+		retry(cli, || {
+			let mut command = wp(wordpress_path, &cli.wp_cli());
+			if update.reinstall {
+				command
+					.args([subcommand, "install", name, "--force"])
+					.arg(format!("--version={}", update.version));
+			} else {
+				command.args([subcommand, "update", name]).args(&cli.wp_update_args);
+			}
+			if cli.jobs <= 1 || cli.dry_run {
+				return stream_command(runner, &mut command, cli.dry_run, cli.timeout());
+			}
+			// Print the output once the update is done, so it isn't interleaved with others'.
+			let output = capture_command(runner, &mut command, cli.timeout())?;
+			String::from_utf8_lossy(&output.stdout)
+				.lines()
+				.for_each(|line| info!("[{name}] {line}"));
+			Ok(())
+		})
+		.and_then(|()| smoke_test(cli, runner, wordpress_path))
+	};
+	// Restores the plugins' states after a failed update, such as one that was interrupted, so they
+	// aren't left deactivated.
+	let clean_up = |pending: &Pending| {
+		let Some(ref active_plugins) = pending.maybe_active_plugins else {
+			return;
+		};
+		if let Err(error) = restore_plugin_states(
+			runner,
+			wordpress_path,
+			&cli.wp_cli(),
+			active_plugins,
+			cli.verify_wp_cli_json_output,
+			cli.dry_run,
+			cli.timeout(),
+		) {
+			warn!("Could not restore the plugins' states after the failed update: {error}");
+		}
+	};
+	let finish = |update: &Update, pending: &mut Pending| {
+		write_event(cli, item_event("update_done", &pending.item));
+		pending.result = (|| {
+			if let Some(ref active_plugins) = pending.maybe_active_plugins {
+				restore_plugin_states(
+					runner,
+					wordpress_path,
+					&cli.wp_cli(),
+					active_plugins,
+					cli.verify_wp_cli_json_output,
+					cli.dry_run,
+					cli.timeout(),
+				)?;
+			}
+			remove(&remove_paths, wordpress_path, cli.allow_remove_outside, cli.dry_run)?;
+			pending.stage = "version check";
+			let maybe_version = if cli.dry_run {
+				None
+			} else {
+				Some(get_item_version(
+					runner,
+					wordpress_path,
+					&cli.wp_cli(),
+					subcommand,
+					&update.name,
+					cli.timeout(),
+				)?)
+			};
+			if let (true, Some(version)) = (update.reinstall, &maybe_version) {
+				if *version != update.version {
+					return Err(io::Error::other(format!(
+						"Reinstalling {subcommand} \"{}\" left it at {version} instead of {}.",
+						update.name, update.version
+					))
+					.into());
+				}
+			}
+			// WP-CLI can succeed without updating plugins and themes from other sources, such as
+			// premium ones with their own update servers.
+			let unchanged = !update.reinstall && maybe_version.as_ref() == Some(&update.version);
+			pending.stage = "commit";
+			if unchanged {
+				warn!(
+					"The {subcommand} \"{}\" is still at {} after updating, so it may be from a source WP-CLI can't update it from.",
+					update.name, update.version
+				);
+				pending.item.not_updated = Some(String::from("external source?"));
+			} else if let Some(ref commit_fn) = maybe_commit_fn {
+				commit_fn(update, pending.maybe_path.as_deref())?;
+				// Recorded now, as the other updates are committed before the items are recorded.
+				if !cli.dry_run {
+					pending.item.commit_sha = git_head(runner, &cli.git_bin, wordpress_path).ok();
+				}
+				write_event(cli, item_event("commit_done", &pending.item));
+			}
+			pending.stage = "post-item hook";
+			if let Some(ref hook) = cli.post_item_hook {
+				run_hook(cli, runner, hook, &item_environment(subcommand, wordpress_path, update))?;
+			}
+			Ok(())
+		})();
+	};
+
+	// Whether to update `update` with --interactive, or `None` to skip the rest of them.
+	let confirm_update = |update: &Update| {
+		let change = if update.reinstall {
+			format!("Reinstall {subcommand} \"{}\" at {}", update.name, update.version)
+		} else {
+			format!(
+				"Update {subcommand} \"{}\" from {} to {}",
+				update.name, update.version, update.update_version
+			)
+		};
+		let confirmation = confirm(cli, &change)?;
+		if confirmation == Some(false) {
+			info!("Skipping {subcommand} \"{}\".", update.name);
+		}
+		OrError::Ok(confirmation)
+	};
+
+	let mut pendings = Vec::with_capacity(updates.len());
+	if cli.jobs <= 1 {
+		for (index, update) in updates.into_iter().enumerate() {
+			match confirm_update(update)? {
+				Some(true) => {}
+				Some(false) => continue,
+				None => break,
+			}
+			let mut pending = prepare(index + 1, update);
+			let start = Instant::now();
+			if pending.result.is_ok() {
+				pending.result = run_update(update).map_err(|error| {
+					roll_back(cli, runner, wordpress_path, &pending.backup, error)
+				});
+				if pending.result.is_ok() {
+					finish(update, &mut pending);
+				} else {
+					clean_up(&pending);
+				}
+			}
+			pending.elapsed += start.elapsed();
+			let failed = pending.result.is_err();
+			pendings.push((update, pending));
+			if failed && !cli.keep_going {
+				break;
+			}
+		}
+	} else {
+		for (index, update) in updates.into_iter().enumerate() {
+			match confirm_update(update)? {
+				Some(true) => {}
+				Some(false) => continue,
+				None => break,
+			}
+			let pending = prepare(index + 1, update);
+			let failed = pending.result.is_err();
+			pendings.push((update, pending));
+			if failed && !cli.keep_going {
+				break;
+			}
+		}
+		// Errors aren't `Send`, so they're passed back from the threads as strings. Updates that
+		// weren't run, as another failed without --keep-going, are left as `None`.
+		let maybe_updates: Vec<Option<&Update>> = pendings
+			.iter()
+			.map(|(update, pending)| pending.result.is_ok().then_some(*update))
+			.collect();
+		let results: Vec<Mutex<Option<_>>> =
+			maybe_updates.iter().map(|_| Mutex::new(None)).collect();
+		let next = AtomicUsize::new(0);
+		let failed = AtomicBool::new(false);
+		let maybe_site = current_site();
+		thread::scope(|scope| {
+			for _ in 0..cli.jobs.min(maybe_updates.len()) {
+				scope.spawn(|| loop {
+					set_current_site(maybe_site.clone());
+					let index = next.fetch_add(1, Ordering::SeqCst);
+					let Some(&maybe_update) = maybe_updates.get(index) else {
+						break;
+					};
+					let Some(update) = maybe_update else {
+						continue;
+					};
+					let name = update.name.as_str();
+					if failed.load(Ordering::SeqCst) && !cli.keep_going {
+						continue;
+					}
+					let start = Instant::now();
+					let result = run_update(update).map_err(|error| {
+						failed.store(true, Ordering::SeqCst);
+						error!("[{name}] {error}");
+						error.to_string()
+					});
+					*results[index].lock().unwrap_or_else(PoisonError::into_inner) =
+						Some((result, start.elapsed()));
+				});
+			}
+		});
+		let mut results = results
+			.into_iter()
+			.map(|result| result.into_inner().unwrap_or_else(PoisonError::into_inner));
+		pendings.retain_mut(|(update, pending)| {
+			let result = results.next().flatten();
+			if pending.result.is_err() {
+				return true;
+			}
+			let start = Instant::now();
+			match result {
+				Some((Ok(()), elapsed)) => {
+					finish(update, pending);
+					pending.elapsed += elapsed;
+				}
+				Some((Err(error), elapsed)) => {
+					pending.result = Err(roll_back(
+						cli,
+						runner,
+						wordpress_path,
+						&pending.backup,
+						io::Error::other(error).into(),
+					));
+					clean_up(pending);
+					pending.elapsed += elapsed;
+				}
+				None => return false,
+			}
+			pending.elapsed += start.elapsed();
+			true
+		});
+	}
+
+	let mut failures = Vec::new();
+	let mut maybe_error = None;
+	for (update, Pending { mut item, stage, result, elapsed, .. }) in pendings {
+		item.duration_secs = Some(elapsed.as_secs_f64());
+		record_item(
+			cli,
+			runner,
+			wordpress_path,
+			format!("update_{subcommand}").as_str(),
+			item,
+			&result,
+			items,
+		)?;
+		match result {
+			Err(error) if cli.keep_going => {
+				error!("Failed to update {subcommand} \"{}\" during {stage}: {error}", update.name);
+				failures.push(format!("{} ({stage}): {error}", update.name));
+			}
+			Err(error) => {
+				maybe_error.get_or_insert(error);
+			}
+			Ok(()) => {}
+		}
+	}
+	if let Some(error) = maybe_error {
+		return Err(error);
+	}
+	if !failures.is_empty() {
+		return Err(io::Error::other(format!(
+			"Failed to update {} {subcommand}(s):\n{}",
+			failures.len(),
+			failures.join("\n")
+		))
+		.into());
+	}
+	Ok(())
+}
+
+/// Returns the path of the directory that the plugin or theme `name` is installed in, or of its
+/// file if it's a single-file plugin.
+fn get_item_path(
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	wp_cli: &WpCli,
+	subcommand: &str,
+	name: &str,
+	maybe_timeout: Option<Duration>,
+) -> OrError<PathBuf> {
+	let get_path = |args: &[&str]| -> OrError<PathBuf> {
+		let output =
+			capture_command(runner, wp_query(wordpress_path, wp_cli).args(args), maybe_timeout)?;
+		let output = String::from_utf8(output.stdout)?;
+		Ok(PathBuf::from(output.lines().last().unwrap_or_default().trim()))
+	};
+	let directory = get_path(&[subcommand, "path"])?;
+	let file = get_path(&[subcommand, "path", name])?;
+	let first_component = file.strip_prefix(&directory)?.components().next().ok_or_else(|| {
+		io::Error::other(format!("Could not find the path of {subcommand} \"{name}\"."))
+	})?;
+	Ok(directory.join(first_component))
+}
+
+/// Returns the installed version of the plugin or theme `name`, depending on `subcommand`.
+fn get_item_version(
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	wp_cli: &WpCli,
+	subcommand: &str,
+	name: &str,
+	maybe_timeout: Option<Duration>,
+) -> OrError<String> {
+	let output = capture_command(
+		runner,
+		wp_query(wordpress_path, wp_cli).args([subcommand, "get", name, "--field=version"]),
+		maybe_timeout,
+	)?;
+	Ok(String::from_utf8(output.stdout)?.lines().last().unwrap_or_default().trim().to_string())
+}
+
+/// Options for the commits made by `git_add_commit`.
+#[derive(Default)]
+pub struct CommitOptions<'a> {
+	/// People to credit with "Co-authored-by" trailers.
+	pub co_authors: &'a [String],
+	/// Whether to GPG-sign the commit.
+	pub sign: bool,
+	/// Key to GPG-sign the commit with, implying `sign`.
+	pub signing_key: Option<&'a str>,
+	/// Whether to add a "Signed-off-by" trailer for the committer.
+	pub sign_off: bool,
+	/// Identity, as in "Name <email>", to author the commit as.
+	pub author: Option<&'a str>,
+	/// Identity, as in "Name <email>", to commit as, which defaults to git's configured one, or
+	/// `author` if git has none.
+	pub committer: Option<&'a str>,
+	/// Whether to skip the repository's pre-commit and commit-msg hooks.
+	pub no_verify: bool,
+}
+
+/// Stages all changes, or only those at `maybe_path`, and commits them with `message`.
+pub fn git_add_commit(
+	runner: &dyn CommandRunner,
+	git_bin: &str,
+	wordpress_path: &str,
+	maybe_path: Option<&Path>,
+	message: &str,
+	options: &CommitOptions,
+	dry_run: bool,
+) -> OrError<()> {
+	stream_command(
+		runner,
+		git(git_bin, wordpress_path)
+			.args(["add", "--all", "--"])
+			.arg(maybe_path.unwrap_or(Path::new("."))),
+		dry_run,
+		None,
+	)?;
+	let nothing_staged = !dry_run
+		&& runner
+			.run_status(git(git_bin, wordpress_path).args(["diff", "--cached", "--quiet"]), None)?
+			.success();
+	if nothing_staged {
+		info!("Nothing to commit for \"{message}\".");
+		return Ok(());
+	}
+	let mut command = git(git_bin, wordpress_path);
+	let has_identity = || -> OrError<bool> {
+		Ok(runner
+			.run_capture(git(git_bin, wordpress_path).args(["var", "GIT_COMMITTER_IDENT"]), None)?
+			.status
+			.success())
+	};
+	let maybe_committer = match options.committer {
+		Some(committer) => Some(committer),
+		None if dry_run || has_identity()? => None,
+		None => Some(options.author.ok_or_else(|| {
+			io::Error::other(
+				"Git has no identity to commit as. Set --git-committer, or git's user.name and user.email.",
+			)
+		})?),
+	};
+	if let Some(committer) = maybe_committer {
+		let (name, email) = split_identity(committer);
+		command.arg("-c").arg(format!("user.name={name}"));
+		command.arg("-c").arg(format!("user.email={email}"));
+	}
+	command.args(["commit", "-m", message]);
+	if let Some(author) = options.author {
+		command.arg(format!("--author={author}"));
+	}
+	for co_author in options.co_authors {
+		command.arg(format!("--trailer=Co-authored-by: {co_author}"));
+	}
+	if let Some(signing_key) = options.signing_key {
+		command.arg(format!("--gpg-sign={signing_key}"));
+	} else if options.sign {
+		command.arg("-S");
+	}
+	if options.sign_off {
+		command.arg("--signoff");
+	}
+	if options.no_verify {
+		command.arg("--no-verify");
+	}
+	stream_command(runner, &mut command, dry_run, None)
+}
+
+/// Pushes the current branch to its namesake on `remote`.
+fn git_push(
+	runner: &dyn CommandRunner,
+	git_bin: &str,
+	wordpress_path: &str,
+	remote: &str,
+	dry_run: bool,
+) -> OrError<()> {
+	stream_command(
+		runner,
+		git(git_bin, wordpress_path).args(["push", remote, "HEAD"]),
+		dry_run,
+		None,
+	)
+}
+
+/// Lists the uncommitted changes in the working tree, in `git status --porcelain` format.
+fn git_dirty_files(
+	runner: &dyn CommandRunner,
+	git_bin: &str,
+	wordpress_path: &str,
+) -> OrError<Vec<String>> {
+	let output = capture_command(
+		runner,
+		git(git_bin, wordpress_path).args(["status", "--porcelain"]),
+		None,
+	)?;
+	Ok(String::from_utf8(output.stdout)?.lines().map(String::from).collect())
+}
+
+/// Adds the file at `path` to the repository's `info/exclude` file if it's in the repository and
+/// isn't already ignored, so it's neither committed nor counted as a change.
+fn git_exclude(
+	runner: &dyn CommandRunner,
+	git_bin: &str,
+	wordpress_path: &str,
+	path: &Path,
+) -> OrError<()> {
+	let mut command = git(git_bin, wordpress_path);
+	command.args(["check-ignore", "--quiet"]).arg(path);
+	// Exits with 1 if the path isn't ignored, or 128 if it's outside of the repository.
+	if runner.run_capture(&mut command, None)?.status.code() != Some(1) {
+		return Ok(());
+	}
+	let output = capture_command(
+		runner,
+		git(git_bin, wordpress_path).args(["rev-parse", "--git-path", "info/exclude"]),
+		None,
+	)?;
+	let exclude_path = Path::new(wordpress_path).join(String::from_utf8(output.stdout)?.trim());
+	let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+		return Ok(());
+	};
+	if let Some(parent) = exclude_path.parent() {
+		fs::create_dir_all(parent)?;
+	}
+	writeln!(OpenOptions::new().create(true).append(true).open(&exclude_path)?, "/{name}")?;
+	info!("Added \"{name}\" to \"{}\" so that it isn't committed.", exclude_path.display());
+	Ok(())
+}
+
+/// Creates a lightweight tag at `HEAD`, warning and skipping it if the tag already exists.
+fn git_tag(
+	runner: &dyn CommandRunner,
+	git_bin: &str,
+	wordpress_path: &str,
+	tag: &str,
+	dry_run: bool,
+) -> OrError<()> {
+	let exists = runner
+		.run_capture(
+			git(git_bin, wordpress_path)
+				.args(["rev-parse", "--verify", "--quiet"])
+				.arg(format!("refs/tags/{tag}")),
+			None,
+		)?
+		.status
+		.success();
+	if exists {
+		warn!("Not creating the tag \"{tag}\" as it already exists.");
+		return Ok(());
+	}
+	stream_command(runner, git(git_bin, wordpress_path).args(["tag", tag]), dry_run, None)
+}
+
+/// Creates and checks out `branch`, or just checks it out if it already exists and `reuse` is set.
+fn git_checkout_branch(
+	runner: &dyn CommandRunner,
+	git_bin: &str,
+	wordpress_path: &str,
+	branch: &str,
+	reuse: bool,
+	dry_run: bool,
+) -> OrError<()> {
+	let exists = runner
+		.run_capture(
+			git(git_bin, wordpress_path)
+				.args(["rev-parse", "--verify", "--quiet"])
+				.arg(format!("refs/heads/{branch}")),
+			None,
+		)?
+		.status
+		.success();
+	match (exists, reuse) {
+		(false, _) => stream_command(
+			runner,
+			git(git_bin, wordpress_path).args(["checkout", "-b", branch]),
+			dry_run,
+			None,
+		),
+		(true, true) => stream_command(
+			runner,
+			git(git_bin, wordpress_path).args(["checkout", branch]),
+			dry_run,
+			None,
+		),
+		(true, false) => Err(io::Error::other(format!(
+			"The branch \"{branch}\" already exists. Use --branch-reuse to switch to it instead."
+		))
+		.into()),
+	}
+}
+
+/// Commits all changes, or only those to `maybe_path` if set, with `message`, pushing afterwards if
+/// `cli.push_each_step` is set.
+fn commit(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	message: &str,
+	maybe_path: Option<&Path>,
+) -> OrError<()> {
+	let options = CommitOptions {
+		co_authors: &cli.co_authors,
+		sign: cli.sign,
+		signing_key: cli.signing_key.as_deref(),
+		sign_off: cli.sign_off,
+		author: cli.git_author.as_deref(),
+		committer: cli.git_committer.as_deref(),
+		no_verify: cli.no_verify,
+	};
+	git_add_commit(
+		runner,
+		&cli.git_bin,
+		wordpress_path,
+		maybe_path,
+		message,
+		&options,
+		cli.dry_run,
+	)?;
+	if cli.push_each_step {
+		git_push(runner, &cli.git_bin, wordpress_path, &cli.remote, cli.dry_run)?;
+	}
+	Ok(())
+}
+
+/// Splits an identity, as in "Name <email>", into its name and email.
+fn split_identity(identity: &str) -> (&str, &str) {
+	let (name, email) = identity.split_once('<').unwrap_or((identity, ""));
+	(name.trim(), email.trim().trim_end_matches('>'))
+}
+
+fn parse_identity(string: &str) -> Result<String, String> {
+	match string.trim().split_once('<') {
+		Some((name, email)) if !name.trim().is_empty() && email.ends_with('>') => {
+			Ok(string.trim().to_string())
+		}
+		_ => Err(String::from("expected an identity of the form \"Name <email>\"")),
+	}
+}
+
+/// Reads the names in the file at `path`, one per line, ignoring blank lines and `#` comments.
+fn read_names(path: &str) -> OrError<Vec<String>> {
+	let contents = fs::read_to_string(path)
+		.map_err(|error| io::Error::other(format!("Could not read \"{path}\": {error}")))?;
+	Ok(contents
+		.lines()
+		.map(|line| line.split('#').next().unwrap_or_default().trim())
+		.filter(|name| !name.is_empty())
+		.map(String::from)
+		.collect())
+}
+
+fn parse_locale(string: &str) -> Result<String, String> {
+	let is_valid = |character: char| character.is_ascii_alphanumeric() || "_-@".contains(character);
+	if !string.is_empty() && string.chars().all(is_valid) {
+		Ok(string.to_string())
+	} else {
+		Err(String::from("expected a locale such as \"de_DE\""))
+	}
+}
+
+fn parse_date_format(string: &str) -> Result<String, String> {
+	if StrftimeItems::new(string).any(|item| item == Item::Error) {
+		Err(String::from("expected a strftime-style format such as \"%Y-%m-%d\""))
+	} else {
+		Ok(string.to_string())
+	}
+}
+
+fn parse_alias(string: &str) -> Result<String, String> {
+	if string.len() > 1 && string.starts_with('@') {
+		Ok(string.to_string())
+	} else {
+		Err(String::from("expected an alias of the form \"@name\""))
+	}
+}
+
+fn unix_time() -> OrError<u64> {
+	Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+fn git_head(runner: &dyn CommandRunner, git_bin: &str, wordpress_path: &str) -> OrError<String> {
+	let output =
+		capture_command(runner, git(git_bin, wordpress_path).args(["rev-parse", "HEAD"]), None)?;
+	Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Outcome of updating a single plugin or theme, or all of core or the translations.
+#[derive(Default, Serialize)]
+pub struct ItemReport {
+	/// Step the update is part of, which the report already groups the items by.
+	#[serde(skip)]
+	pub step: String,
+	/// Name of the plugin or theme.
+	pub name: Option<String>,
+	pub from_version: Option<String>,
+	pub to_version: Option<String>,
+	/// Path of the database backup made before the update.
+	pub backup: Option<String>,
+	/// Path of the archive of the files backed up before the update.
+	pub files_backup: Option<String>,
+	/// Commit that the update was made in.
+	pub commit_sha: Option<String>,
+	/// Plugins that were deactivated to update core.
+	pub deactivated_plugins: Vec<String>,
+	/// Wall-clock time the update took, in seconds.
+	pub duration_secs: Option<f64>,
+	/// Why the update succeeded without changing the version, in which case it isn't committed.
+	pub not_updated: Option<String>,
+	pub error: Option<String>,
+}
+
+impl ItemReport {
+	/// Whether the update succeeded and changed anything.
+	pub fn is_updated(&self) -> bool {
+		self.error.is_none() && self.not_updated.is_none()
+	}
+}
+
+/// Outcome of a step.
+#[derive(Serialize)]
+pub struct StepReport {
+	pub step: String,
+	pub items: Vec<ItemReport>,
+	pub error: Option<String>,
+	/// Wall-clock time the step took, in seconds.
+	pub duration_secs: f64,
+}
+
+/// Completes `item` with the outcome of its update given by `result`, and the commit it was made in
+/// unless that's already set, then writes it to the audit log and adds it to `items`.
+fn record_item(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	action: &str,
+	mut item: ItemReport,
+	result: &OrError<()>,
+	items: &mut Vec<ItemReport>,
+) -> OrError<()> {
+	if item.commit_sha.is_none() {
+		item.commit_sha = match result {
+			Ok(()) if item.not_updated.is_none() && cli.commits_each_step() && !cli.dry_run => {
+				git_head(runner, &cli.git_bin, wordpress_path).ok()
+			}
+			_ => None,
+		};
+	}
+	item.error = result.as_ref().err().map(ToString::to_string);
+	if item.error.is_some() {
+		write_event(cli, item_event("item_failed", &item));
+	}
+	write_audit_log(cli, wordpress_path, action, &item)?;
+	items.push(item);
+	Ok(())
+}
+
+/// Outcome of the run, as returned by `main_loop` and written by --report-json and
+/// --webhook-url.
+#[derive(Serialize)]
+pub struct RunReport {
+	pub wordpress_path: String,
+	pub dry_run: bool,
+	/// Either "success" or "failure".
+	pub status: &'static str,
+	/// Number of items whose versions were changed, which is always 0 for a dry run. Translations
+	/// have no versions, so they count whenever they are updated.
+	pub updated: usize,
+	/// Number of items that failed to update.
+	pub failed: usize,
+	pub steps: Vec<StepReport>,
+	pub error: Option<String>,
+}
+
+impl RunReport {
+	fn new(cli: &Cli, steps: Vec<StepReport>, result: &OrError<()>) -> Self {
+		let items = || steps.iter().flat_map(|step| &step.items);
+		RunReport {
+			wordpress_path: cli.wordpress_path.clone(),
+			dry_run: cli.dry_run,
+			status: if result.is_ok() { "success" } else { "failure" },
+			updated: items()
+				.filter(|item| {
+					!cli.dry_run
+						&& item.is_updated()
+						&& (item.from_version.is_none() || item.from_version != item.to_version)
+				})
+				.count(),
+			failed: items().filter(|item| item.error.is_some()).count(),
+			steps,
+			error: result.as_ref().err().map(ToString::to_string),
+		}
+	}
+}
+
+/// Writes `report` to the --report-json file, replacing it atomically so that it's valid even if
+/// the run is interrupted.
+fn write_report(cli: &Cli, report: &impl Serialize) -> OrError<()> {
+	let Some(ref template) = cli.report_json else {
+		return Ok(());
+	};
+	let path = substitute_placeholders(
+		template,
+		&Placeholders::for_site(&cli.wordpress_path).with_time(cli)?,
+	);
+	let temporary_path = format!("{path}.tmp");
+	fs::write(&temporary_path, serde_json::to_string_pretty(report)?)?;
+	fs::rename(temporary_path, path)?;
+	Ok(())
+}
+
+/// Outcome of updating several installations with the `sites` action, as returned by `sites` and
+/// written by --report-json.
+#[derive(Serialize)]
+pub struct SitesReport {
+	/// Number of items that were updated across all the installations.
+	pub updated: usize,
+	/// Number of items that failed to update across all the installations.
+	pub failed: usize,
+	/// Number of installations whose runs failed.
+	pub failed_sites: usize,
+	pub sites: Vec<RunReport>,
+}
+
+/// POSTs `report` as JSON to `url`.
+#[cfg(feature = "webhook")]
+fn send_webhook(url: &str, report: &RunReport) -> OrError<()> {
+	ureq::post(url)
+		.set("Content-Type", "application/json")
+		.send_string(&serde_json::to_string(report)?)?;
+	Ok(())
+}
+
+/// GETs each of `cli.smoke_test_urls`, or the site URL if none are given, failing if any responds
+/// with a server error or not at all. URLs starting with "/" are relative to the site URL.
+#[cfg(feature = "smoke-test")]
+fn smoke_test(cli: &Cli, runner: &dyn CommandRunner, wordpress_path: &str) -> OrError<()> {
+	if !cli.smoke_test && cli.smoke_test_urls.is_empty() {
+		return Ok(());
+	}
+	let site_url = || -> OrError<String> {
+		let mut command = wp_query(wordpress_path, &cli.wp_cli());
+		let output =
+			capture_command(runner, command.args(["option", "get", "siteurl"]), cli.timeout())?;
+		Ok(String::from_utf8(output.stdout)?.trim().trim_end_matches('/').to_string())
+	};
+	let urls = if cli.smoke_test_urls.is_empty() {
+		vec![site_url()?]
+	} else {
+		cli.smoke_test_urls
+			.iter()
+			.map(|url| {
+				Ok(if url.starts_with('/') { format!("{}{url}", site_url()?) } else { url.clone() })
+			})
+			.collect::<OrError<_>>()?
+	};
+	let mut agent = ureq::AgentBuilder::new();
+	if let Some(timeout) = cli.timeout() {
+		agent = agent.timeout(timeout);
+	}
+	let agent = agent.build();
+	for url in urls {
+		if cli.dry_run {
+			info!("Would smoke-test \"{url}\".");
+			continue;
+		}
+		let status = match agent.get(&url).call() {
+			Ok(response) => response.status(),
+			Err(ureq::Error::Status(status, _)) => status,
+			Err(error) => {
+				return Err(
+					io::Error::other(format!("Smoke-testing \"{url}\" failed: {error}")).into()
+				)
+			}
+		};
+		if status >= 500 {
+			return Err(io::Error::other(format!(
+				"Smoke-testing \"{url}\" failed as it responded with status {status}."
+			))
+			.into());
+		}
+		info!("Smoke-tested \"{url}\", which responded with status {status}.");
+	}
+	Ok(())
+}
+
+#[cfg(not(feature = "smoke-test"))]
+fn smoke_test(_cli: &Cli, _runner: &dyn CommandRunner, _wordpress_path: &str) -> OrError<()> {
+	Ok(())
+}
+
+/// Appends a line describing the outcome of an update action to the audit log, if one is set.
+fn write_audit_log(
+	cli: &Cli,
+	wordpress_path: &str,
+	action: &str,
+	item: &ItemReport,
+) -> OrError<()> {
+	#[derive(Serialize)]
+	struct AuditEntry<'a> {
+		timestamp: u64,
+		site: String,
+		action: &'a str,
+		name: Option<&'a str>,
+		from_version: Option<&'a str>,
+		to_version: Option<&'a str>,
+		commit_sha: Option<&'a str>,
+		result: &'a str,
+	}
+
+	let Some(ref template) = cli.audit_log else {
+		return Ok(());
+	};
+	let audit_log = substitute_placeholders(template, &Placeholders::for_site(wordpress_path));
+	if cli.dry_run {
+		return Ok(());
+	}
+	let entry = AuditEntry {
+		timestamp: unix_time()?,
+		site: fs::canonicalize(wordpress_path)
+			.map_or_else(|_| wordpress_path.to_string(), |path| path.display().to_string()),
+		action,
+		name: item.name.as_deref(),
+		from_version: item.from_version.as_deref(),
+		to_version: item.to_version.as_deref(),
+		commit_sha: item.commit_sha.as_deref(),
+		result: item.error.as_deref().unwrap_or("success"),
+	};
+	let mut file = OpenOptions::new().create(true).append(true).open(audit_log)?;
+	writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+	file.sync_data()?;
+	Ok(())
+}
+
+/// A progress event written to --events.
+#[derive(Default, Serialize)]
+struct Event<'a> {
+	timestamp: u64,
+	/// Installation being updated by the `sites` action.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	site: Option<String>,
+	event: &'a str,
+	step: &'a str,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	name: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	from_version: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	to_version: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	backup: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	files_backup: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	commit_sha: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	error: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	duration_secs: Option<f64>,
+}
+
+/// The `event` of the update described by `item`.
+fn item_event<'a>(event: &'a str, item: &'a ItemReport) -> Event<'a> {
+	Event {
+		event,
+		step: &item.step,
+		name: item.name.as_deref(),
+		from_version: item.from_version.as_deref(),
+		to_version: item.to_version.as_deref(),
+		backup: item.backup.as_deref(),
+		files_backup: item.files_backup.as_deref(),
+		commit_sha: item.commit_sha.as_deref(),
+		error: item.error.as_deref(),
+		duration_secs: item.duration_secs,
+		..Default::default()
+	}
+}
+
+/// Writes `event` as a JSON line to the --events file, or stdout if it's "-". Failures are only
+/// logged as warnings, so that they don't fail the update.
+fn write_event(cli: &Cli, mut event: Event) {
+	let Some(ref template) = cli.events else {
+		return;
+	};
+	let path = substitute_placeholders(template, &Placeholders::for_site(&cli.wordpress_path));
+	let result = (|| -> OrError<()> {
+		event.timestamp = unix_time()?;
+		event.site = current_site();
+		let line = serde_json::to_string(&event)?;
+		if path == "-" {
+			writeln!(io::stdout().lock(), "{line}")?;
+		} else {
+			writeln!(OpenOptions::new().create(true).append(true).open(&path)?, "{line}")?;
+		}
+		Ok(())
+	})();
+	if let Err(error) = result {
+		warn!("Could not write the {} event to \"{path}\": {error}", event.event);
+	}
+}
+
+#[derive(clap::ValueEnum, Clone)]
+pub enum Step {
+	Core,
+	Plugins,
+	Themes,
+	Translations,
+	Database,
+	Cache,
+	Packages,
+}
+
+/// What to do when WordPress core files don't match their checksums after updating.
+#[derive(clap::ValueEnum, Clone)]
+pub enum VerifyChecksums {
+	/// Fails the core update.
+	Fail,
+	/// Logs a warning and carries on.
+	Warn,
+}
+
+/// How to update translations.
+#[derive(clap::ValueEnum, Clone)]
+pub enum TranslationsMethod {
+	/// Runs `wp language core update`, then `wp language plugin update --all` and `wp language
+	/// theme update --all`. These are part of WP-CLI's interface, so they're unaffected by changes
+	/// to WordPress's internals.
+	Language,
+	/// Runs WordPress's language pack upgrader through `wp eval`, as the dashboard's "Update
+	/// Translations" button does. This updates every translation WordPress reports as outdated in
+	/// one batch, but relies on WordPress's internal classes.
+	Eval,
+}
+
+/// When to print colors.
+#[derive(clap::ValueEnum, Clone)]
+pub enum ColorChoice {
+	/// Prints colors if stdout is a terminal and the NO_COLOR environment variable isn't set.
+	Auto,
+	Always,
+	Never,
+}
+
+#[derive(clap::Subcommand, Clone)]
+pub enum Action {
+	/// Lists the available updates for the selected steps without applying them.
+	Check,
+	/// Prints a script that sets up tab-completion of the arguments in the given shell.
+	Completions {
+		/// Shell to print the script for. Bash, Elvish, fish, PowerShell and Zsh are supported.
+		shell: Shell,
+	},
+	/// Imports a database backup, decompressing it first if it's gzipped.
+	Restore {
+		/// Backup to import. If omitted, the backups found using --database-file-path are listed
+		/// to pick from, defaulting to the newest.
+		path: Option<String>,
+	},
+	/// Updates several WordPress installations as with --wordpress-path, summarising them
+	/// together. Their logs are prefixed with their paths.
+	Sites {
+		/// Paths of the WordPress installations to update.
+		paths: Vec<String>,
+		/// File listing more paths of WordPress installations to update, one per line, ignoring
+		/// blank lines and `#` comments.
+		#[arg(long, value_name = "PATH")]
+		file: Option<String>,
+		/// Number of installations to update in parallel.
+		#[arg(
+			long,
+			value_name = "N",
+			default_value_t = 1,
+			value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+		)]
+		site_jobs: usize,
+	},
+}
+
+#[derive(Parser, Clone)]
+#[command(
+	version,
+	about,
+	long_about = None,
+	after_help = "Exit codes:\n  0  Everything was updated\n  1  Some plugins or themes failed to update with --keep-going\n  2  The run failed\n  3  Nothing was updated and no database, cache or packages step was run"
+)]
+pub struct Cli {
+	#[command(subcommand)]
+	pub action: Option<Action>,
+	/// Only updates the active theme and its parent theme.
+	#[arg(long, global = true)]
+	pub active_themes_only: bool,
+	/// Allows starting with uncommitted changes in the working tree, which will be included in the
+	/// first commit.
+	#[arg(long)]
+	pub allow_dirty: bool,
+	/// Allows removing paths that resolve to somewhere outside of the WordPress installation.
+	#[arg(long)]
+	pub allow_remove_outside: bool,
+	/// Updates translations even when the only active locale is en_US.
+	#[arg(long)]
+	pub force_translations: bool,
+	/// Only updates the translations for these locales, such as `de_DE`. As WP-CLI's language
+	/// commands can't be limited to locales, translations are updated as with
+	/// `--translations-method eval` when this is given.
+	#[arg(long = "locale", value_name = "LOCALE", value_parser = parse_locale)]
+	pub locales: Vec<String>,
+	/// File to append a JSON line to for every update action, across runs. Supports the
+	/// `{wordpress_path}` placeholder.
+	#[arg(long)]
+	pub audit_log: Option<String>,
+	/// Backs up the database before updating core or translations even if there are no updates
+	/// available for them.
+	#[arg(long)]
+	pub backup_always: bool,
+	/// Also archives the files of each plugin or theme before updating it, wp-content before
+	/// updating core, and wp-content/languages before updating translations, to paths templated by
+	/// --database-file-path with ".tar" in place of ".sql". These are restored too by
+	/// --rollback-on-failure.
+	#[arg(long)]
+	pub backup_files: bool,
+	/// Table to back up instead of the whole database, such as `{prefix}options`, where `{prefix}`
+	/// is the installation's table prefix. Can be given multiple times.
+	#[arg(long = "backup-table", value_name = "TABLE")]
+	pub backup_tables: Vec<String>,
+	/// Table to leave out of database backups, such as `{prefix}actionscheduler_logs`, where
+	/// `{prefix}` is the installation's table prefix. Can be given multiple times.
+	#[arg(long = "backup-exclude-table", value_name = "TABLE", conflicts_with = "backup_tables")]
+	pub backup_exclude_tables: Vec<String>,
+	/// Compresses database backups with gzip, appending ".gz" to their paths.
+	#[arg(long)]
+	pub compress_backups: bool,
+	/// Only updates WordPress core to newer minor versions of its installed major version, logging
+	/// when a major update is held back.
+	#[arg(long)]
+	pub core_minor_only: bool,
+	/// Version to update WordPress core to instead of the latest one. Downgrading to an older
+	/// version also needs `--wp-update-arg=--force`.
+	#[arg(long, value_name = "VERSION", conflicts_with = "core_minor_only")]
+	pub core_version: Option<String>,
+	/// TOML file to read arguments from, keyed by their names in snake case, as in
+	/// `exclude_plugins = ["akismet"]`. Arguments given on the command line take precedence. Defaults
+	/// to "updatewp.toml" in the working directory, if it exists.
+	#[arg(long, global = true, value_name = "PATH")]
+	pub config: Option<String>,
+	/// When to print colors, such as those in WP-CLI's output, which are removed otherwise.
+	#[arg(long, global = true, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+	pub color: ColorChoice,
+	/// People to credit with a "Co-authored-by" trailer on each commit.
+	#[arg(long = "co-author", value_name = "NAME <EMAIL>", value_parser = parse_identity)]
+	pub co_authors: Vec<String>,
+	/// Seconds after which to kill a WP-CLI or hook command that's still running, failing it.
+	#[arg(long, value_name = "SECONDS")]
+	pub command_timeout: Option<u64>,
+	/// Template of the commit messages, overriding the default ones. Supports the `{step}` ("core",
+	/// "plugin", "theme" or "translations"), `{name}`, `{version}`, `{update_version}`,
+	/// `{separator}` and `{prefix}` (--commit-prefix followed by the separator, if given)
+	/// placeholders.
+	#[arg(long)]
+	pub commit_template: Option<String>,
+	/// A string to add to the start of commit messages.
+	#[arg(short = 'p', long)]
+	pub commit_prefix: Option<String>,
+	/// Path to use for storing database backups. Supports the `{wordpress_path}`, `{step}`,
+	/// `{unix_time}`, `{date}` and `{version}` placeholders, the last being the version updated
+	/// from, which is empty for translations.
+	#[arg(short, long, global = true, default_value_t = String::from("{wordpress_path}/../{unix_time}.{step}.sql"))]
+	pub database_file_path: String,
+	/// strftime-style format of the `{date}` placeholder.
+	#[arg(long, global = true, default_value_t = String::from(DATE_FORMAT), value_parser = parse_date_format)]
+	pub date_format: String,
+	/// File to write a JSON line to as each step and update progresses, or "-" for stdout. Each has
+	/// the "event" (step_start, item_start, backup_done, update_done, commit_done, item_failed or
+	/// step_done), a "timestamp" and the "step", along with the plugin or theme's "name", versions,
+	/// backups, commit or error where known. Supports the `{wordpress_path}` placeholder.
+	#[arg(long, global = true, value_name = "PATH")]
+	pub events: Option<String>,
+	/// Plugins to exclude from updates.
+	#[arg(short = 'e', long, global = true)]
+	pub exclude_plugins: Vec<String>,
+	/// Themes to exclude from updates.
+	#[arg(short = 't', long, global = true)]
+	pub exclude_themes: Vec<String>,
+	/// File of plugins to exclude from updates in addition to --exclude-plugins, one per line.
+	/// Blank lines and `#` comments are ignored.
+	#[arg(long, global = true, value_name = "PATH")]
+	pub exclude_plugins_file: Option<String>,
+	/// File of themes to exclude from updates in addition to --exclude-themes, one per line. Blank
+	/// lines and `#` comments are ignored.
+	#[arg(long, global = true, value_name = "PATH")]
+	pub exclude_themes_file: Option<String>,
+	/// Branch to create and make the commits on, which is also what gets pushed. Supports the
+	/// `{unix_time}` and `{date}` placeholders.
+	#[arg(long)]
+	pub branch: Option<String>,
+	/// Switches to the branch given by --branch if it already exists, instead of aborting.
+	#[arg(long)]
+	pub branch_reuse: bool,
+	/// Number of database backups to keep for each (sub-)step, deleting older ones.
+	#[arg(long, value_name = "N")]
+	pub keep_backups: Option<usize>,
+	/// Only logs hooks that exit unsuccessfully, instead of aborting.
+	#[arg(long)]
+	pub ignore_hook_errors: bool,
+	/// Appends the changelog URL of each plugin updated from WordPress.org to its commit message.
+	#[arg(long)]
+	pub include_changelog_url: bool,
+	/// Asks before applying each update of core, a plugin or a theme, unless stdin isn't a
+	/// terminal. Answering "a" applies all the rest without asking, and "s" skips the rest of the
+	/// step.
+	#[arg(long)]
+	pub interactive: bool,
+	/// Number of plugin or theme updates to run in parallel. Their database backups are all made
+	/// before any of them are run, so it can't be used with --rollback-on-failure, which would
+	/// undo the successful updates' database changes too.
+	#[arg(
+		short,
+		long,
+		value_name = "N",
+		default_value_t = 1,
+		conflicts_with = "rollback_on_failure",
+		value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+	)]
+	pub jobs: usize,
+	/// Continues with the next plugin or theme if one fails to update, and with the steps after
+	/// theirs, reporting all failures at the end of the step and of the run.
+	#[arg(long)]
+	pub keep_going: bool,
+	/// File to lock for the duration of the run, so that another run on the same installation fails
+	/// instead of running concurrently. It's added to the repository's `info/exclude` file if it's
+	/// in the repository. Supports the `{wordpress_path}` placeholder.
+	#[arg(long, global = true, default_value_t = String::from("{wordpress_path}/.updatewp.lock"))]
+	pub lock_file: String,
+	/// File to append every command that's run and all of its output to, even when --quiet hides
+	/// it. Supports the `{wordpress_path}`, `{unix_time}` and `{date}` placeholders.
+	#[arg(long, global = true, value_name = "PATH")]
+	pub log_file: Option<String>,
+	/// Keeps the site in maintenance mode while updating, turning it off at the end even if an
+	/// update fails.
+	#[arg(long)]
+	pub maintenance: bool,
+	/// MiB of disk space to leave free when backing up the database, on top of one and a half
+	/// times the database's size, failing before the backup if there isn't enough. Not checked
+	/// unless given and more than 0.
+	#[arg(
+		long,
+		value_name = "MIB",
+		value_parser = clap::builder::RangedU64ValueParser::<u64>::new().range(..=u64::MAX / MIB)
+	)]
+	pub min_free_space: Option<u64>,
+	/// Holds back updates of plugins from WordPress.org that were released less than this many
+	/// days ago. Updates whose release dates aren't known, such as those from elsewhere, are
+	/// applied with a warning.
+	#[cfg(feature = "min-age")]
+	#[arg(long, global = true, value_name = "DAYS")]
+	pub min_age_days: Option<u32>,
+	/// Only updates these plugins, if given. --exclude-plugins still applies to them.
+	#[arg(long, global = true)]
+	pub only_plugins: Vec<String>,
+	/// Only updates these themes, if given. --exclude-themes still applies to them.
+	#[arg(long, global = true)]
+	pub only_themes: Vec<String>,
+	/// Optimises the database at the end of the run, as the database step does, but only if
+	/// anything was updated. Does nothing if the database step is taken.
+	#[arg(long, global = true)]
+	pub optimize_after: bool,
+	/// Plugins to only apply minor and patch updates to, holding back updates that increase the
+	/// major version. Plugins whose versions aren't semantic versions are always updated.
+	#[arg(long, global = true, value_name = "PLUGIN")]
+	pub plugin_minor_only: Vec<String>,
+	/// Plugin to reinstall at its installed version, such as to repair files that fail checksum
+	/// verification, committing it as a reinstall. Plugins whose updates are selected are updated
+	/// instead. Can be given multiple times.
+	#[arg(long = "reinstall-plugin", global = true, value_name = "PLUGIN")]
+	pub reinstall_plugins: Vec<String>,
+	/// Disables backing-up of the database before each (sub-)step.
+	#[arg(short = 'b', long)]
+	pub no_backup_database: bool,
+	/// Disables committing after each (sub-)step.
+	#[arg(short = 'c', long)]
+	pub no_commit: bool,
+	/// Leaves the plugins deactivated to update core deactivated afterwards, such as to test them
+	/// manually.
+	#[arg(long)]
+	pub no_reactivate: bool,
+	/// Skips the repository's pre-commit and commit-msg hooks when committing.
+	#[arg(long)]
+	pub no_verify: bool,
+	/// Prints the commands that would modify the installation instead of running them.
+	#[arg(short = 'n', long, global = true)]
+	pub dry_run: bool,
+	/// Shell command to run after each step. Receives the `UPDATEWP_STEP` and
+	/// `UPDATEWP_WORDPRESS_PATH` environment variables.
+	#[arg(long, value_name = "COMMAND")]
+	pub post_hook: Option<String>,
+	/// Shell command to run after each plugin or theme update. Receives the environment variables
+	/// of --post-hook, plus `UPDATEWP_ITEM_NAME`, `UPDATEWP_ITEM_VERSION` and
+	/// `UPDATEWP_ITEM_UPDATE_VERSION`.
+	#[arg(long, value_name = "COMMAND")]
+	pub post_item_hook: Option<String>,
+	/// Shell command to run before each step, with the environment variables of --post-hook.
+	#[arg(long, value_name = "COMMAND")]
+	pub pre_hook: Option<String>,
+	/// Shell command to run before each plugin or theme update, with the environment variables of
+	/// --post-item-hook.
+	#[arg(long, value_name = "COMMAND")]
+	pub pre_item_hook: Option<String>,
+	/// Pushes the commits once all steps are done.
+	#[arg(long)]
+	pub push: bool,
+	/// Pushes after each commit instead of once at the end.
+	#[arg(long)]
+	pub push_each_step: bool,
+	/// Only logs warnings and errors, besides the summary at the end of the run.
+	#[arg(short, long, global = true, conflicts_with = "verbose")]
+	pub quiet: bool,
+	/// Git remote to push to.
+	#[arg(long, default_value_t = String::from("origin"))]
+	pub remote: String,
+	/// Restores any plugin activation states changed by each plugin update.
+	#[arg(long)]
+	pub preserve_plugin_state: bool,
+	/// Updates plugins after the plugins they require, warning about required plugins that are
+	/// missing.
+	#[arg(long)]
+	pub resolve_plugin_dependencies: bool,
+	/// Number of times to retry a failed update command before treating it as failed.
+	#[arg(long, value_name = "N", default_value_t = 0)]
+	pub retries: usize,
+	/// Seconds to wait before retrying a failed update command, doubling for each retry after the
+	/// first.
+	#[arg(long, value_name = "SECONDS", default_value_t = 10)]
+	pub retry_delay: u64,
+	/// Repairs the database before optimising it in the database step.
+	#[arg(long)]
+	pub repair_database: bool,
+	/// Restores the database, and the files with --backup-files, from the backups made before a
+	/// (sub-)step if its update fails, then aborts.
+	#[arg(long)]
+	pub rollback_on_failure: bool,
+	/// Makes a single commit summarising all of the updates once all steps are done, instead of
+	/// committing after each (sub-)step.
+	#[arg(long, conflicts_with = "no_commit")]
+	pub squash: bool,
+	/// String to use as a separator in commit messages.
+	#[arg(long, default_value_t = String::from(": "))]
+	pub separator: String,
+	/// GPG-signs the commits.
+	#[arg(long)]
+	pub sign: bool,
+	/// Key to GPG-sign the commits with, implying --sign.
+	#[arg(long, value_name = "KEY_ID")]
+	pub signing_key: Option<String>,
+	/// Adds a "Signed-off-by" trailer for the committer to the commits, as needed by projects
+	/// using the Developer Certificate of Origin.
+	#[arg(long)]
+	pub sign_off: bool,
+	/// Doesn't check that --wordpress-path is an installed WordPress before updating it.
+	#[arg(long)]
+	pub skip_validation: bool,
+	/// Skips plugins and themes whose updates aren't from WordPress.org, such as premium ones with
+	/// their own update servers, which WP-CLI often can't update.
+	#[arg(long)]
+	pub skip_external: bool,
+	/// Smoke-tests the site after each update by GETting --smoke-test-url, or the site URL if that
+	/// isn't given, failing the update if it responds with a server error.
+	#[cfg(feature = "smoke-test")]
+	#[arg(long)]
+	pub smoke_test: bool,
+	/// URL to smoke-test after each update, implying --smoke-test. Can be given multiple times, and
+	/// can be a path starting with "/" to be relative to the site URL.
+	#[cfg(feature = "smoke-test")]
+	#[arg(long = "smoke-test-url", value_name = "URL")]
+	pub smoke_test_urls: Vec<String>,
+	/// Runs WP-CLI on the given host through its `--ssh` option, instead of on the installation at
+	/// --wordpress-path, which is then only used for Git. The installation's path on the host should
+	/// be included, as in `user@host/path`.
+	#[arg(
+		long,
+		global = true,
+		value_name = "[SCHEME:][USER@]HOST[:PORT][PATH]",
+		conflicts_with = "wp_alias"
+	)]
+	pub ssh: Option<String>,
+	/// The steps and order of steps taken. The database step, which optimises the database, the
+	/// cache step, which flushes the object cache and rewrite rules, and the packages step, which
+	/// updates the installed WP-CLI packages, aren't taken unless given.
+	#[arg(short, long, global = true, value_enum, default_values_t = [Step::Core, Step::Themes, Step::Plugins, Step::Translations])]
+	pub steps: Vec<Step>,
+	/// Identity to author the commits as, instead of git's configured one.
+	#[arg(long, value_name = "NAME <EMAIL>", value_parser = parse_identity)]
+	pub git_author: Option<String>,
+	/// Identity to make the commits as, instead of git's configured one. Defaults to --git-author
+	/// if git has no identity configured.
+	#[arg(long, value_name = "NAME <EMAIL>", value_parser = parse_identity)]
+	pub git_committer: Option<String>,
+	/// Git executable to run.
+	#[arg(long, global = true, value_name = "PATH", default_value_t = String::from("git"))]
+	pub git_bin: String,
+	/// Tags the commit of each successful WordPress core update.
+	#[arg(long)]
+	pub tag_core: bool,
+	/// Name of the tags created by --tag-core. Supports the `{old_version}` and `{new_version}`
+	/// placeholders.
+	#[arg(long, default_value_t = String::from("wp-core-{new_version}"))]
+	pub tag_template: String,
+	/// How to update translations.
+	#[arg(long, value_enum, default_value_t = TranslationsMethod::Language)]
+	pub translations_method: TranslationsMethod,
+	/// Path to write a JSON report of the outcome of each step and item to at the end of the run.
+	/// Supports the `{wordpress_path}`, `{unix_time}` and `{date}` placeholders.
+	#[arg(long, value_name = "PATH")]
+	pub report_json: Option<String>,
+	/// Paths to remove after each (sub-)step, before committing. Environment variables, as in
+	/// `$HOME` or `${HOME}`, are expanded, except in the default, which removes a directory
+	/// literally named "$XDG_CACHE_HOME" that WP-CLI can leave in the installation.
+	#[arg(short, long, default_values_t = [String::from(DEFAULT_REMOVE_PATH)])]
+	pub remove_paths: Vec<String>,
+	/// Logs the commands that change the installation, or with -vv all commands run and what they
+	/// print. The RUST_LOG environment variable can also be used to set the log level.
+	#[arg(short, long, global = true, action = ArgAction::Count)]
+	pub verbose: u8,
+	/// Verifies the WordPress core files against their checksums after updating core.
+	#[arg(long, value_enum, value_name = "ON_MISMATCH", num_args = 0..=1, default_missing_value = "fail")]
+	pub verify_checksums: Option<VerifyChecksums>,
+	/// Logs any non-JSON text discarded from WP-CLI's JSON output and any fields that weren't
+	/// understood.
+	#[arg(long)]
+	pub verify_wp_cli_json_output: bool,
+	/// URL to POST a JSON summary of the run to once it's done.
+	#[cfg(feature = "webhook")]
+	#[arg(long, value_name = "URL")]
+	pub webhook_url: Option<String>,
+	/// Path of the WordPress installation to update.
+	#[arg(short, long, global = true, default_value_t = String::from("./"))]
+	pub wordpress_path: String,
+	/// WP-CLI executable to run.
+	#[arg(long, global = true, value_name = "PATH", default_value_t = String::from("wp"))]
+	pub wp_bin: String,
+	/// Runs WP-CLI's queries, such as listing the plugins and their updates, without loading the
+	/// plugins, which speeds them up on sites with many plugins. Updates provided by plugins'
+	/// own updaters aren't listed then. Commands that change the site still load the plugins.
+	#[arg(long, global = true)]
+	pub wp_skip_plugins: bool,
+	/// Runs WP-CLI's queries without loading the themes, as --wp-skip-plugins does for plugins.
+	#[arg(long, global = true)]
+	pub wp_skip_themes: bool,
+	/// Argument to add to the end of the `wp core update`, `wp plugin update` and `wp theme update`
+	/// commands, such as `--minor`.
+	#[arg(long = "wp-update-arg", value_name = "ARG", allow_hyphen_values = true)]
+	pub wp_update_args: Vec<String>,
+	/// WP-CLI alias, as defined in `wp-cli.yml`, to run WP-CLI on instead of the installation at
+	/// --wordpress-path, which is then only used for Git.
+	#[arg(long, global = true, value_name = "@NAME", value_parser = parse_alias)]
+	pub wp_alias: Option<String>,
+}
+
+impl Cli {
+	/// Parses the command-line arguments, taking those that weren't given from the config file.
+	pub fn parse_with_config() -> OrError<Self> {
+		let mut cli = Cli::parse_args_and_config()?;
+		if let Some(ref path) = cli.exclude_plugins_file {
+			let names = read_names(path)?;
+			cli.exclude_plugins.extend(names);
+		}
+		if let Some(ref path) = cli.exclude_themes_file {
+			let names = read_names(path)?;
+			cli.exclude_themes.extend(names);
+		}
+		Ok(cli)
+	}
+
+	fn parse_args_and_config() -> OrError<Self> {
+		let args: Vec<OsString> = env::args_os().collect();
+		let command = Cli::command();
+		let matches = command.clone().get_matches_from(&args);
+		let path = match matches.get_one::<String>("config") {
+			Some(path) => path.clone(),
+			None if Path::new(CONFIG_FILE_NAME).exists() => String::from(CONFIG_FILE_NAME),
+			None => return Ok(Cli::from_arg_matches(&matches)?),
+		};
+		let config: toml::Table = fs::read_to_string(&path)
+			.map_err(|error| io::Error::other(format!("Could not read \"{path}\": {error}")))?
+			.parse()?;
+		let mut config_args = Vec::new();
+		for (key, value) in config {
+			let Some((long, action)) = command
+				.get_arguments()
+				.find(|arg| arg.get_id() == key.as_str() && key != "config")
+				.and_then(|arg| Some((arg.get_long()?, arg.get_action())))
+			else {
+				return Err(
+					io::Error::other(format!("Unknown key \"{key}\" in \"{path}\".")).into()
+				);
+			};
+			if matches.value_source(&key) == Some(ValueSource::CommandLine) {
+				continue;
+			}
+			let values = match value {
+				toml::Value::Array(values) => values,
+				value => vec![value],
+			};
+			for value in values {
+				match (value, action) {
+					(toml::Value::Boolean(set), ArgAction::SetTrue) => {
+						if set {
+							config_args.push(OsString::from(format!("--{long}")));
+						}
+					}
+					// Counted flags, like --verbose, are given as many times as the count.
+					(toml::Value::Integer(count), ArgAction::Count) => {
+						let count = u8::try_from(count).map_err(|_| {
+							io::Error::other(format!(
+								"Invalid count {count} for \"{key}\" in \"{path}\", which should be from 0 to {}.",
+								u8::MAX
+							))
+						})?;
+						config_args.extend((0..count).map(|_| OsString::from(format!("--{long}"))));
+					}
+					(value, ArgAction::Count) => {
+						return Err(io::Error::other(format!(
+							"Invalid value {value} for \"{key}\" in \"{path}\", which should be a count, such as 2."
+						))
+						.into())
+					}
+					(toml::Value::String(value), _) => {
+						config_args.push(OsString::from(format!("--{long}={value}")))
+					}
+					(
+						value @ (toml::Value::Integer(_)
+						| toml::Value::Float(_)
+						| toml::Value::Boolean(_)),
+						_,
+					) => config_args.push(OsString::from(format!("--{long}={value}"))),
+					(value, _) => {
+						return Err(io::Error::other(format!(
+							"Unsupported value {value} for \"{key}\" in \"{path}\"."
+						))
+						.into())
+					}
+				}
+			}
+		}
+		// The config file's arguments go before the command line's, so that they aren't taken as
+		// arguments of a subcommand.
+		let args =
+			args.iter().take(1).cloned().chain(config_args).chain(args.iter().skip(1).cloned());
+		Cli::try_parse_from(args).map_err(|error| {
+			// Only the first line of clap's error is kept, without its "error: " prefix.
+			let error = error.to_string();
+			let error = error.lines().next().unwrap_or_default();
+			io::Error::other(format!(
+				"Invalid value in \"{path}\": {}",
+				error.strip_prefix("error: ").unwrap_or(error)
+			))
+			.into()
+		})
+	}
+
+	/// Level to log at as set by --quiet and --verbose.
+	pub fn log_level(&self) -> LevelFilter {
+		match (self.quiet, self.verbose) {
+			(true, _) => LevelFilter::Warn,
+			(false, 0) => LevelFilter::Info,
+			(false, 1) => LevelFilter::Debug,
+			(false, _) => LevelFilter::Trace,
+		}
+	}
+
+	/// Whether to print colors as set by --color.
+	pub fn colors(&self) -> bool {
+		match self.color {
+			ColorChoice::Auto => {
+				env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+					&& io::stdout().is_terminal()
+			}
+			ColorChoice::Always => true,
+			ColorChoice::Never => false,
+		}
+	}
+
+	/// Whether to back up anything before each (sub-)step.
+	fn backs_up(&self) -> bool {
+		!self.no_backup_database || self.backup_files
+	}
+
+	/// Whether to commit after each (sub-)step, rather than not at all or only once with --squash.
+	fn commits_each_step(&self) -> bool {
+		!self.no_commit && !self.squash
+	}
+
+	/// The time limit given by --command-timeout.
+	fn timeout(&self) -> Option<Duration> {
+		self.command_timeout.map(Duration::from_secs)
+	}
+
+	/// How to run WP-CLI, as given by --wp-bin, --ssh and --wp-alias.
+	pub fn wp_cli(&self) -> WpCli {
+		WpCli {
+			bin: self.wp_bin.clone(),
+			maybe_remote: self
+				.wp_alias
+				.clone()
+				.or_else(|| self.ssh.as_ref().map(|ssh| format!("--ssh={ssh}"))),
+			skip_plugins: self.wp_skip_plugins,
+			skip_themes: self.wp_skip_themes,
+		}
+	}
+}
+
+impl AsRef<Cli> for Cli {
+	fn as_ref(&self) -> &Cli {
+		self
+	}
+}
+
+/// Fills in --commit-template, or the default template for `step`, with the details of an update.
+fn commit_message(
+	cli: &Cli,
+	commit_prefix: &str,
+	step: &str,
+	name: &str,
+	version: &str,
+	update_version: &str,
+) -> String {
+	let template = cli.commit_template.as_deref().unwrap_or(match step {
+		"core" => "{prefix}Update WordPress Core{separator}{version} -> {update_version}",
+		"translations" => "{prefix}Update translations",
+		_ => "{prefix}Update {step}{separator}{name}{separator}{version} -> {update_version}",
+	});
+	template
+		.replace("{step}", step)
+		.replace("{name}", name)
+		.replace("{version}", version)
+		.replace("{update_version}", update_version)
+		.replace("{separator}", &cli.separator)
+		.replace("{prefix}", commit_prefix)
+}
+
+/// Fills in --commit-template, as for an update from `version` to itself, or otherwise the default
+/// template for reinstalling the plugin `name` at `version`.
+fn reinstall_commit_message(cli: &Cli, commit_prefix: &str, name: &str, version: &str) -> String {
+	match cli.commit_template {
+		Some(_) => commit_message(cli, commit_prefix, "plugin", name, version, version),
+		None => {
+			let separator = &cli.separator;
+			format!("{commit_prefix}Reinstall plugin{separator}{name}{separator}{version}")
+		}
+	}
+}
+
+/// URL of the changelog of the WordPress.org plugin `slug`.
+fn wordpress_org_changelog_url(slug: &str) -> String {
+	format!("https://wordpress.org/plugins/{slug}/#developers")
+}
+
+/// The major version of the WordPress `version`, which is its first two components, as in "6.4"
+/// for "6.4.1".
+fn major_version(version: &str) -> &str {
+	version.match_indices('.').nth(1).map_or(version, |(index, _)| &version[..index])
+}
+
+/// Tags the current commit if --tag-core is given and WordPress core was updated.
+fn tag_core(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+	version: &str,
+	update_version: &str,
+) -> OrError<()> {
+	if cli.tag_core && update_version != version {
+		let tag = cli
+			.tag_template
+			.replace("{old_version}", version)
+			.replace("{new_version}", update_version);
+		git_tag(runner, &cli.git_bin, wordpress_path, &tag, cli.dry_run)?;
+	}
+	Ok(())
+}
+
+fn update_core(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	commit_prefix: &str,
+	wordpress_path: &str,
+	items: &mut Vec<ItemReport>,
+) -> OrError<()> {
+	let version = get_wordpress_version(runner, wordpress_path, &cli.wp_cli(), cli.timeout())?;
+	let core_updates = if cli.core_version.is_none()
+		&& (cli.core_minor_only || cli.interactive || (cli.backs_up() && !cli.backup_always))
+	{
+		get_core_updates(cli, runner, wordpress_path)?
+	} else {
+		Vec::new()
+	};
+	let (core_updates, held_back_updates): (Vec<_>, Vec<_>) =
+		core_updates.iter().partition(|update| {
+			!cli.core_minor_only || major_version(&update.version) == major_version(&version)
+		});
+	if let Some(update) = held_back_updates.first() {
+		info!(
+			"Holding back the major update of WordPress core to {} due to --core-minor-only, so it needs to be applied manually.",
+			update.version
+		);
+	}
+	let maybe_target =
+		cli.core_version.as_deref().or(core_updates.first().map(|update| update.version.as_str()));
+	if let Some(target) = maybe_target {
+		if confirm(cli, &format!("Update WordPress core from {version} to {target}"))? != Some(true)
+		{
+			info!("Skipping the core update.");
+			return Ok(());
+		}
+	}
+	let maybe_backup_fn = if !cli.backs_up() {
+		None
+	} else if !cli.backup_always && cli.core_version.is_none() && core_updates.is_empty() {
+		info!("Not backing up as there are no core updates available.");
+		None
+	} else {
+		Some(|| {
+			backup_for_step(
+				cli,
+				runner,
+				wordpress_path,
+				"update_core",
+				&version,
+				Path::new("wp-content"),
+			)
+		})
+	};
+	let active_plugins = get_active_plugins(
+		runner,
+		wordpress_path,
+		&cli.wp_cli(),
+		cli.verify_wp_cli_json_output,
+		cli.timeout(),
+	)?;
+	let update_fn = || {
+		let update_result = (|| {
+			if !active_plugins.is_empty() {
+				info!("Deactivating plugins to update core: {}.", active_plugins.join(", "));
+			}
+			activate_plugins(
+				runner,
+				wordpress_path,
+				&cli.wp_cli(),
+				active_plugins.as_ref(),
+				false,
+				cli.dry_run,
+				cli.timeout(),
+			)?;
+			retry(cli, || {
+				stream_command(
+					runner,
+					wp(wordpress_path, &cli.wp_cli())
+						.args(["core", "update"])
+						.args(cli.core_minor_only.then_some("--minor"))
+						.args(
+							cli.core_version.as_ref().map(|version| format!("--version={version}")),
+						)
+						.args(&cli.wp_update_args),
+					cli.dry_run,
+					cli.timeout(),
+				)
+			})
+		})();
+		// Reactivate the plugins even if the update failed, so the site isn't left without them.
+		let activate_result = if cli.no_reactivate {
+			if !active_plugins.is_empty() {
+				warn!(
+					"Leaving plugins deactivated due to --no-reactivate: {}.",
+					active_plugins.join(", ")
+				);
+			}
+			Ok(())
+		} else {
+			activate_plugins(
+				runner,
+				wordpress_path,
+				&cli.wp_cli(),
+				active_plugins.as_ref(),
+				true,
+				cli.dry_run,
+				cli.timeout(),
+			)
+			.map_err(|error| {
+				io::Error::other(format!(
+					"Reactivating plugins {} failed: {error}",
+					active_plugins.join(", ")
+				))
+				.into()
+			})
+		};
+		match (update_result, activate_result) {
+			(Ok(()), activate_result) => activate_result,
+			(Err(update_error), Ok(())) => Err(update_error),
+			(Err(update_error), Err(activate_error)) => {
+				Err(io::Error::other(format!("{update_error}\n{activate_error}")).into())
+			}
+		}?;
+		if let Some(ref verify_checksums) = cli.verify_checksums {
+			let result = stream_command(
+				runner,
+				wp(wordpress_path, &cli.wp_cli()).args(["core", "verify-checksums"]),
+				cli.dry_run,
+				cli.timeout(),
+			);
+			match (result, verify_checksums) {
+				(Err(error), VerifyChecksums::Warn) => {
+					warn!("WordPress core files don't match their checksums: {error}");
+				}
+				(result, _) => result?,
+			}
+		}
+		Ok(())
+	};
+	let maybe_commit_fn = if !cli.commits_each_step() {
 		None
 	} else {
-		Some(|name: &_| {
-			let substituted = cli.database_file_path.replace("{wordpress_path}", wordpress_path);
-			let substituted =
-				substituted.replace("{step}", format!("update_theme.{name}").as_str());
-			let substituted = substituted.replace("{unix_time}", unix_time()?.to_string().as_str());
-			backup_database(wordpress_path, substituted.as_ref())
+		Some(|| {
+			let update_version =
+				get_wordpress_version(runner, wordpress_path, &cli.wp_cli(), cli.timeout())?;
+			commit(
+				cli,
+				runner,
+				wordpress_path,
+				&commit_message(cli, commit_prefix, "core", "WordPress", &version, &update_version),
+				None,
+			)?;
+			tag_core(cli, runner, wordpress_path, &version, &update_version)
 		})
 	};
-	let maybe_commit_fn = if cli.no_commit {
+	let mut item = ItemReport {
+		step: String::from("core"),
+		from_version: Some(version.clone()),
+		deactivated_plugins: active_plugins.clone(),
+		..Default::default()
+	};
+	let start = Instant::now();
+	let result =
+		update(cli, runner, wordpress_path, &mut item, maybe_backup_fn, update_fn, maybe_commit_fn);
+	item.duration_secs = Some(start.elapsed().as_secs_f64());
+	item.to_version =
+		get_wordpress_version(runner, wordpress_path, &cli.wp_cli(), cli.timeout()).ok();
+	if result.is_ok() && !cli.dry_run && item.to_version.as_ref() == Some(&version) {
+		item.not_updated = Some(String::from("already up to date"));
+		item.commit_sha = None;
+	}
+	record_item(cli, runner, wordpress_path, "update_core", item, &result, items)?;
+	result
+}
+
+fn update_plugins(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	commit_prefix: &str,
+	wordpress_path: &str,
+	items: &mut Vec<ItemReport>,
+) -> OrError<()> {
+	let maybe_backup_fn = if !cli.backs_up() {
 		None
 	} else {
-		Some(|name: &_, version: &_, update_version: &_| {
-			git_add_commit(
+		Some(|name: &_, version: &_| {
+			backup_for_step(
+				cli,
+				runner,
 				wordpress_path,
-				format!(
-					"{commit_prefix}Update theme{0}{name}{0}{version} -> {update_version}",
-					cli.separator
-				)
-				.as_str(),
+				format!("update_plugin.{name}").as_str(),
+				version,
+				&item_files_path(wordpress_path, "plugin", name),
 			)
 		})
 	};
-	update_in_steps(
-		wordpress_path,
-		&cli.remove_paths,
-		maybe_backup_database_fn,
-		&cli.exclude_themes,
-		maybe_commit_fn,
-		"theme",
-	)
+	let maybe_commit_fn = if !cli.commits_each_step() {
+		None
+	} else {
+		Some(|update: &Update, maybe_path: Option<&_>| {
+			if update.reinstall {
+				let message =
+					reinstall_commit_message(cli, commit_prefix, &update.name, &update.version);
+				return commit(cli, runner, wordpress_path, &message, maybe_path);
+			}
+			let mut message = commit_message(
+				cli,
+				commit_prefix,
+				"plugin",
+				&update.name,
+				&update.version,
+				&update.update_version,
+			);
+			if cli.include_changelog_url && update.is_from_wordpress_org() {
+				message.push_str(&format!(
+					"\n\nChangelog: {}",
+					wordpress_org_changelog_url(&update.name)
+				));
+			}
+			commit(cli, runner, wordpress_path, &message, maybe_path)
+		})
+	};
+	update_in_steps(cli, runner, wordpress_path, maybe_backup_fn, maybe_commit_fn, "plugin", items)
+}
+
+fn update_themes(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	commit_prefix: &str,
+	wordpress_path: &str,
+	items: &mut Vec<ItemReport>,
+) -> OrError<()> {
+	let maybe_backup_fn = if !cli.backs_up() {
+		None
+	} else {
+		Some(|name: &_, version: &_| {
+			backup_for_step(
+				cli,
+				runner,
+				wordpress_path,
+				format!("update_theme.{name}").as_str(),
+				version,
+				&item_files_path(wordpress_path, "theme", name),
+			)
+		})
+	};
+	let maybe_commit_fn = if !cli.commits_each_step() {
+		None
+	} else {
+		Some(|update: &Update, maybe_path: Option<&_>| {
+			commit(
+				cli,
+				runner,
+				wordpress_path,
+				&commit_message(
+					cli,
+					commit_prefix,
+					"theme",
+					&update.name,
+					&update.version,
+					&update.update_version,
+				),
+				maybe_path,
+			)
+		})
+	};
+	update_in_steps(cli, runner, wordpress_path, maybe_backup_fn, maybe_commit_fn, "theme", items)
 }
 
-fn update_translations(cli: &Cli, commit_prefix: &str, wordpress_path: &str) -> OrError<()> {
-	let maybe_backup_database_fn = if cli.no_backup_database {
+fn update_translations(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	commit_prefix: &str,
+	wordpress_path: &str,
+	items: &mut Vec<ItemReport>,
+) -> OrError<()> {
+	if !cli.force_translations {
+		let locales = get_active_locales(
+			runner,
+			wordpress_path,
+			&cli.wp_cli(),
+			cli.verify_wp_cli_json_output,
+			cli.timeout(),
+		)?;
+		if locales.iter().all(|locale| locale == "en_US") {
+			info!(
+				"Skipping translations as there are no active non-English locales (found: {}).",
+				if locales.is_empty() { String::from("none") } else { locales.join(", ") }
+			);
+			return Ok(());
+		}
+	}
+	let available = has_translation_updates(cli, runner, wordpress_path)?;
+	let maybe_backup_fn = if !cli.backs_up() {
+		None
+	} else if !cli.backup_always && !available {
+		info!("Not backing up as there are no translation updates available.");
 		None
 	} else {
+		if cli.database_file_path.contains("{version}") {
+			info!("Leaving the {{version}} placeholder empty for the translations' backups.");
+		}
 		Some(|| {
-			let substituted = cli.database_file_path.replace("{wordpress_path}", wordpress_path);
-			let substituted = substituted.replace("{step}", "update_translations");
-			let substituted = substituted.replace("{unix_time}", unix_time()?.to_string().as_str());
-			backup_database(wordpress_path, substituted.as_ref())
+			backup_for_step(
+				cli,
+				runner,
+				wordpress_path,
+				"update_translations",
+				"",
+				Path::new("wp-content/languages"),
+			)
 		})
 	};
-	let update_fn = || {
-		stream_command(
-			Command::new("wp")
-				.args([
-					"eval",
-					"require_once ABSPATH . 'wp-admin/includes/class-wp-upgrader.php'; (new Language_Pack_Upgrader(new Language_Pack_Upgrader_Skin(['url' => 'update-core.php?action=do-translation-upgrade', 'nonce' => 'upgrade-translations', 'title' => __('Update Translations'), 'context' => WP_LANG_DIR])))->bulk_upgrade();",
-					format!("--path={wordpress_path}").as_str()
-				])
+	// Updates translations as the dashboard's "Update Translations" button does.
+	const LANGUAGE_PACK_UPGRADER: &str = "(new Language_Pack_Upgrader(new Language_Pack_Upgrader_Skin(['url' => 'update-core.php?action=do-translation-upgrade', 'nonce' => 'upgrade-translations', 'title' => __('Update Translations'), 'context' => WP_LANG_DIR])))";
+	let upgrade_translations = if cli.locales.is_empty() {
+		format!("{LANGUAGE_PACK_UPGRADER}->bulk_upgrade();")
+	} else {
+		// Locales only contain characters that are safe in PHP strings, so their JSON array is also a
+		// PHP array. Upgrading an empty list of updates would upgrade all of them instead.
+		format!(
+			"$updates = array_values(array_filter(wp_get_translation_updates(), function ($update) {{ return in_array($update->language, {}, true); }})); if ($updates) {{ {LANGUAGE_PACK_UPGRADER}->bulk_upgrade($updates); }}",
+			serde_json::to_string(&cli.locales)?
 		)
 	};
-	let maybe_commit_fn = if cli.no_commit {
+	let upgrade_translations = format!(
+		"require_once ABSPATH . 'wp-admin/includes/class-wp-upgrader.php'; {upgrade_translations}"
+	);
+	// WP-CLI's language commands can't be limited to locales.
+	let method =
+		if cli.locales.is_empty() { &cli.translations_method } else { &TranslationsMethod::Eval };
+	let update_fn = || match method {
+		TranslationsMethod::Language => {
+			for args in [
+				&["language", "core", "update"][..],
+				&["language", "plugin", "update", "--all"],
+				&["language", "theme", "update", "--all"],
+			] {
+				retry(cli, || {
+					stream_command(
+						runner,
+						wp(wordpress_path, &cli.wp_cli()).args(args),
+						cli.dry_run,
+						cli.timeout(),
+					)
+				})?;
+			}
+			Ok(())
+		}
+		TranslationsMethod::Eval => retry(cli, || {
+			stream_command(
+				runner,
+				wp(wordpress_path, &cli.wp_cli()).args(["eval", upgrade_translations.as_str()]),
+				cli.dry_run,
+				cli.timeout(),
+			)
+		}),
+	};
+	let maybe_commit_fn = if !cli.commits_each_step() {
 		None
 	} else {
 		Some(|| {
-			git_add_commit(wordpress_path, format!("{commit_prefix}Update translations").as_str())
+			commit(
+				cli,
+				runner,
+				wordpress_path,
+				&commit_message(cli, commit_prefix, "translations", "", "", ""),
+				None,
+			)
 		})
 	};
-	update(wordpress_path, &cli.remove_paths, maybe_backup_database_fn, update_fn, maybe_commit_fn)
+	let mut item = ItemReport { step: String::from("translations"), ..Default::default() };
+	let start = Instant::now();
+	let result =
+		update(cli, runner, wordpress_path, &mut item, maybe_backup_fn, update_fn, maybe_commit_fn);
+	item.duration_secs = Some(start.elapsed().as_secs_f64());
+	if result.is_ok() && !available {
+		item.not_updated = Some(String::from("no updates available"));
+		item.commit_sha = None;
+	}
+	record_item(cli, runner, wordpress_path, "update_translations", item, &result, items)?;
+	result
+}
+
+/// Repairs the database if --repair-database is set, then optimises it. Nothing is committed, as
+/// the database isn't tracked by Git.
+fn optimize_database(cli: &Cli, runner: &dyn CommandRunner, wordpress_path: &str) -> OrError<()> {
+	if cli.repair_database {
+		stream_command(
+			runner,
+			wp(wordpress_path, &cli.wp_cli()).args(["db", "repair"]),
+			cli.dry_run,
+			cli.timeout(),
+		)?;
+	}
+	stream_command(
+		runner,
+		wp(wordpress_path, &cli.wp_cli()).args(["db", "optimize"]),
+		cli.dry_run,
+		cli.timeout(),
+	)
+}
+
+/// Flushes the object cache, then the rewrite rules if WP-CLI has the command for it.
+fn flush_caches(cli: &Cli, runner: &dyn CommandRunner, wordpress_path: &str) -> OrError<()> {
+	stream_command(
+		runner,
+		wp(wordpress_path, &cli.wp_cli()).args(["cache", "flush"]),
+		cli.dry_run,
+		cli.timeout(),
+	)?;
+	let mut command = wp(wordpress_path, &cli.wp_cli());
+	command
+		.args(["cli", "has-command", "rewrite flush"])
+		.stdout(Stdio::null())
+		.stderr(Stdio::null());
+	if !runner.run_status(&mut command, cli.timeout())?.success() {
+		info!("Not flushing the rewrite rules as `wp rewrite flush` isn't available.");
+		return Ok(());
+	}
+	stream_command(
+		runner,
+		wp(wordpress_path, &cli.wp_cli()).args(["rewrite", "flush"]),
+		cli.dry_run,
+		cli.timeout(),
+	)
+}
+
+/// Updates the installed WP-CLI packages. Nothing is backed up or committed, as they're installed
+/// for WP-CLI rather than in the site.
+fn update_packages(cli: &Cli, runner: &dyn CommandRunner, wordpress_path: &str) -> OrError<()> {
+	stream_command(
+		runner,
+		wp(wordpress_path, &cli.wp_cli()).args(["package", "update"]),
+		cli.dry_run,
+		cli.timeout(),
+	)
+}
+
+/// Asks which of the `backups` to use, defaulting to the newest. Picks the newest without asking
+/// when stdin isn't a terminal.
+fn pick_backup(backups: &[PathBuf]) -> OrError<&PathBuf> {
+	let newest = backups.first().ok_or_else(|| io::Error::other("No backups were found."))?;
+	if !io::stdin().is_terminal() {
+		return Ok(newest);
+	}
+	for (index, backup) in backups.iter().enumerate() {
+		println!("{}) {}", index + 1, backup.display());
+	}
+	loop {
+		print!("Backup to restore [1]: ");
+		io::stdout().flush()?;
+		let mut answer = String::new();
+		io::stdin().read_line(&mut answer)?;
+		let answer = answer.trim();
+		if answer.is_empty() {
+			return Ok(newest);
+		}
+		match answer.parse::<usize>().ok().and_then(|number| backups.get(number.checked_sub(1)?)) {
+			Some(backup) => return Ok(backup),
+			None => println!("Please enter a number from 1 to {}.", backups.len()),
+		}
+	}
+}
+
+/// Imports the database backup at `maybe_path`, or one picked from those found using
+/// `cli.database_file_path`.
+pub fn restore(cli: &Cli, runner: &dyn CommandRunner, maybe_path: Option<&str>) -> OrError<()> {
+	let wordpress_path = cli.wordpress_path.as_str();
+	let path = match maybe_path {
+		Some(path) => PathBuf::from(path),
+		None => {
+			let template = substitute_placeholders(
+				&cli.database_file_path,
+				&Placeholders::for_site(wordpress_path),
+			);
+			let mut backups = find_backups(&template)?;
+			backups.extend(find_backups(format!("{template}.gz").as_str())?);
+			backups.sort_by_key(|path| {
+				Reverse(fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+			});
+			pick_backup(&backups)?.clone()
+		}
+	};
+	import_database(runner, wordpress_path, &cli.wp_cli(), &path, cli.dry_run, cli.timeout())?;
+	if !cli.dry_run {
+		info!("Restored \"{}\".", path.display());
+	}
+	Ok(())
+}
+
+/// Prints `rows` as a table with aligned columns, the first row being the header.
+fn print_table<const N: usize>(rows: &[[String; N]]) {
+	let mut widths = [0; N];
+	for row in rows {
+		for (width, cell) in widths.iter_mut().zip(row) {
+			*width = (*width).max(cell.chars().count());
+		}
+	}
+	for row in rows {
+		let cells: Vec<String> =
+			row.iter().zip(widths).map(|(cell, width)| format!("{cell:width$}")).collect();
+		println!("{}", cells.join("  ").trim_end());
+	}
+}
+
+/// Prints the completion script for `shell` to stdout.
+pub fn completions(shell: Shell) {
+	let mut command = Cli::command();
+	let name = command.get_name().to_string();
+	clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+}
+
+/// Prints the available updates for the steps given by `cli.steps`, without applying them.
+pub fn check(cli: &Cli, runner: &dyn CommandRunner) -> OrError<()> {
+	#[derive(Deserialize, Serialize)]
+	struct LanguageUpdate {
+		language: String,
+	}
+	#[derive(Deserialize, Serialize)]
+	struct Package {
+		name: String,
+		version: String,
+		update: String,
+		update_version: Option<String>,
+	}
+
+	let wordpress_path = cli.wordpress_path.as_str();
+	let wp_cli = &cli.wp_cli();
+	let mut rows = vec![["Type", "Name", "Current", "Available"].map(String::from)];
+	for step in cli.steps.deref() {
+		match step {
+			Step::Core => {
+				if let Some(update) =
+					get_core_updates(cli, runner, wordpress_path)?.into_iter().next()
+				{
+					let version =
+						get_wordpress_version(runner, wordpress_path, wp_cli, cli.timeout())?;
+					rows.push([
+						String::from("core"),
+						String::from("WordPress"),
+						version,
+						update.version,
+					]);
+				}
+			}
+			Step::Plugins | Step::Themes => {
+				let subcommand = if let Step::Plugins = step { "plugin" } else { "theme" };
+				let updates = fetch_updates(cli, runner, wordpress_path, subcommand)?;
+				for update in select_updates(cli, runner, wordpress_path, subcommand, updates)? {
+					rows.push([
+						subcommand.to_string(),
+						update.name,
+						update.version,
+						update.update_version,
+					]);
+				}
+			}
+			Step::Translations => {
+				let updates: Vec<LanguageUpdate> = wp_json(
+					runner,
+					wp_query(wordpress_path, wp_cli).args([
+						"language",
+						"core",
+						"list",
+						"--fields=language",
+						"--update=available",
+						"--format=json",
+					]),
+					cli.verify_wp_cli_json_output,
+					cli.timeout(),
+				)?;
+				for update in updates {
+					rows.push([
+						String::from("translation"),
+						update.language,
+						String::from("-"),
+						String::from("-"),
+					]);
+				}
+			}
+			Step::Packages => {
+				let packages: Vec<Package> = wp_json(
+					runner,
+					wp_query(wordpress_path, wp_cli).args([
+						"package",
+						"list",
+						"--fields=name,version,update,update_version",
+						"--format=json",
+					]),
+					cli.verify_wp_cli_json_output,
+					cli.timeout(),
+				)?;
+				for package in packages.into_iter().filter(|package| package.update == "available")
+				{
+					rows.push([
+						String::from("package"),
+						package.name,
+						package.version,
+						package.update_version.unwrap_or_else(|| String::from("-")),
+					]);
+				}
+			}
+			Step::Database | Step::Cache => {}
+		}
+	}
+	if rows.len() == 1 {
+		println!("Everything is up to date.");
+	} else {
+		print_table(&rows);
+	}
+	Ok(())
+}
+
+/// Checks that there's an installed WordPress at `wordpress_path` with `wp core is-installed`, so a
+/// wrong path fails clearly before anything is backed up or committed.
+fn validate_installation(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	wordpress_path: &str,
+) -> OrError<()> {
+	let mut command = wp_query(wordpress_path, &cli.wp_cli());
+	let output = runner.run_capture(command.args(["core", "is-installed"]), cli.timeout())?;
+	if output.status.success() {
+		return Ok(());
+	}
+	let stderr = strip_ansi(&String::from_utf8_lossy(&output.stderr));
+	let mut message = format!(
+		"No WordPress installation found at \"{wordpress_path}\". Check --wordpress-path, or use --skip-validation if it's correct."
+	);
+	if !stderr.trim().is_empty() {
+		message += &format!("\n{}", stderr.trim());
+	}
+	Err(io::Error::other(message).into())
+}
+
+/// Exclusive lock on the --lock-file for the duration of a run, which records the process holding
+/// it until it's dropped.
+struct Lock {
+	file: File,
+}
+
+impl Lock {
+	/// Locks the file at `path`, failing immediately if another run holds it.
+	fn acquire(path: &str) -> OrError<Self> {
+		let mut file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(path)
+			.map_err(|error| io::Error::other(format!("Could not open \"{path}\": {error}")))?;
+		let mut holder = String::new();
+		file.read_to_string(&mut holder)?;
+		let holder = holder.trim();
+		// Called through fs4, as `File::try_lock` needs a newer Rust than the rest of the crate.
+		match fs4::FileExt::try_lock(&file) {
+			Ok(()) => {}
+			Err(fs4::TryLockError::WouldBlock) => {
+				return Err(io::Error::other(format!(
+					"Another run is in progress, as \"{path}\" is locked (by {}).",
+					if holder.is_empty() { "an unknown process" } else { holder }
+				))
+				.into())
+			}
+			Err(fs4::TryLockError::Error(error)) => return Err(error.into()),
+		}
+		// The lock is released if a run crashes, but the file is only emptied when one finishes.
+		if !holder.is_empty() {
+			warn!(
+				"The previous run ({holder}) didn't finish, so the installation may need checking."
+			);
+		}
+		file.set_len(0)?;
+		file.seek(SeekFrom::Start(0))?;
+		write!(
+			file,
+			"PID {}, started at {}",
+			std::process::id(),
+			Local::now().format("%Y-%m-%d %H:%M:%S")
+		)?;
+		file.sync_data()?;
+		Ok(Lock { file })
+	}
+}
+
+impl Drop for Lock {
+	fn drop(&mut self) {
+		let _ = self.file.set_len(0);
+	}
+}
+
+pub fn main_loop(cli_ref: &Cli, runner: &dyn CommandRunner) -> OrError<RunReport> {
+	let (report, result) = run_and_report(cli_ref, runner)?;
+	result.map(|()| report)
+}
+
+/// Updates each of the installations at `paths` and those listed in the file at `maybe_file` as
+/// `main_loop` would, with up to `site_jobs` at a time, prefixing their logs with their paths.
+/// Failed runs are included in the report rather than stopping the others.
+pub fn sites(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	paths: &[String],
+	maybe_file: Option<&str>,
+	site_jobs: usize,
+) -> OrError<SitesReport> {
+	let mut paths = paths.to_vec();
+	if let Some(file) = maybe_file {
+		paths.extend(read_names(file)?);
+	}
+	if paths.is_empty() {
+		return Err(io::Error::other("No WordPress installations were given to update.").into());
+	}
+	let paths = paths.as_slice();
+	let reports: Vec<Mutex<Option<RunReport>>> = paths.iter().map(|_| Mutex::new(None)).collect();
+	let next = AtomicUsize::new(0);
+	thread::scope(|scope| {
+		for _ in 0..site_jobs.min(paths.len()) {
+			scope.spawn(|| loop {
+				let index = next.fetch_add(1, Ordering::SeqCst);
+				let Some(path) = paths.get(index) else {
+					break;
+				};
+				set_current_site(Some(path.clone()));
+				let mut site_cli = cli.clone();
+				site_cli.action = None;
+				site_cli.wordpress_path.clone_from(path);
+				// The reports are written together once all the installations are updated.
+				site_cli.report_json = None;
+				let report = match run_and_report(&site_cli, runner) {
+					Ok((report, Ok(()))) => report,
+					Ok((report, Err(error))) => {
+						error!("{error}");
+						report
+					}
+					Err(error) => {
+						error!("{error}");
+						RunReport::new(&site_cli, Vec::new(), &Err(error))
+					}
+				};
+				*reports[index].lock().unwrap_or_else(PoisonError::into_inner) = Some(report);
+			});
+		}
+	});
+	set_current_site(None);
+	let sites: Vec<RunReport> = reports
+		.into_iter()
+		.filter_map(|report| report.into_inner().unwrap_or_else(PoisonError::into_inner))
+		.collect();
+	let report = SitesReport {
+		updated: sites.iter().map(|site| site.updated).sum(),
+		failed: sites.iter().map(|site| site.failed).sum(),
+		failed_sites: sites.iter().filter(|site| site.error.is_some()).count(),
+		sites,
+	};
+	write_report(cli, &report)?;
+	Ok(report)
+}
+
+/// Runs the steps as `main_loop` does, returning the report along with the outcome of the run, so
+/// the report is available even if the run failed. Only fails if the run couldn't be started or
+/// reported.
+pub fn run_and_report(
+	cli_ref: &Cli,
+	runner: &dyn CommandRunner,
+) -> OrError<(RunReport, OrError<()>)> {
+	let wordpress_path = cli_ref.wordpress_path.as_str();
+	// Validated first, so the lock file isn't created somewhere that isn't an installation.
+	if !cli_ref.skip_validation {
+		validate_installation(cli_ref, runner, wordpress_path)?;
+	}
+	let _maybe_lock = if cli_ref.dry_run {
+		None
+	} else {
+		let path =
+			substitute_placeholders(&cli_ref.lock_file, &Placeholders::for_site(wordpress_path));
+		let lock = Lock::acquire(&path)?;
+		if !cli_ref.no_commit {
+			git_exclude(runner, &cli_ref.git_bin, wordpress_path, Path::new(&path))?;
+		}
+		Some(lock)
+	};
+	let mut steps = Vec::new();
+	let result = run_steps(cli_ref, runner, &mut steps);
+	let report = RunReport::new(cli_ref, steps, &result);
+	let optimizes = cli_ref.optimize_after
+		&& report.updated > 0
+		&& !cli_ref.steps.iter().any(|step| matches!(step, Step::Database));
+	if optimizes {
+		info!("Optimising the database as {} item(s) were updated.", report.updated);
+		if let Err(error) = optimize_database(cli_ref, runner, wordpress_path) {
+			warn!("Could not optimise the database: {error}");
+		}
+	}
+	write_report(cli_ref, &report)?;
+	#[cfg(feature = "webhook")]
+	if let Some(ref url) = cli_ref.webhook_url {
+		if let Err(error) = send_webhook(url, &report) {
+			warn!("Could not send the webhook notification: {error}");
+		}
+	}
+	Ok((report, result))
 }
 
-pub fn main_loop(cli_ref: &Cli) -> OrError<()> {
+/// Runs the steps given by `cli_ref.steps`, returning what was done in each of them.
+fn run_steps(
+	cli_ref: &Cli,
+	runner: &dyn CommandRunner,
+	steps: &mut Vec<StepReport>,
+) -> OrError<()> {
 	let commit_prefix =
 		if let (false, Some(commit_prefix)) = (cli_ref.no_commit, cli_ref.commit_prefix.as_ref()) {
 			format!("{commit_prefix}{0}", cli_ref.separator)
@@ -397,13 +4400,614 @@ pub fn main_loop(cli_ref: &Cli) -> OrError<()> {
 		};
 	let commit_prefix = commit_prefix.as_str();
 	let wordpress_path = cli_ref.wordpress_path.as_str();
-	for step in cli_ref.steps.deref() {
-		match step {
-			Step::Core => update_core(cli_ref, commit_prefix, wordpress_path),
-			Step::Plugins => update_plugins(cli_ref, commit_prefix, wordpress_path),
-			Step::Themes => update_themes(cli_ref, commit_prefix, wordpress_path),
-			Step::Translations => update_translations(cli_ref, commit_prefix, wordpress_path),
-		}?;
+	if !cli_ref.no_commit && !cli_ref.allow_dirty {
+		let dirty_files = git_dirty_files(runner, &cli_ref.git_bin, wordpress_path)?;
+		if !dirty_files.is_empty() {
+			return Err(io::Error::other(format!(
+				"The working tree has uncommitted changes, which would be included in the update commits. Commit or stash them, or use --allow-dirty.\n{}",
+				dirty_files.join("\n")
+			))
+			.into());
+		}
+	}
+	if let Some(ref branch) = cli_ref.branch {
+		let branch = substitute_placeholders(branch, &Placeholders::default().with_time(cli_ref)?);
+		git_checkout_branch(
+			runner,
+			&cli_ref.git_bin,
+			wordpress_path,
+			&branch,
+			cli_ref.branch_reuse,
+			cli_ref.dry_run,
+		)?;
+	}
+	let steps_result = (|| {
+		let mut failures = Vec::new();
+		for step in cli_ref.steps.deref() {
+			check_interrupted()?;
+			let start = Instant::now();
+			let step_name = step
+				.to_possible_value()
+				.map_or_else(String::new, |value| value.get_name().to_string());
+			write_event(
+				cli_ref,
+				Event { event: "step_start", step: &step_name, ..Default::default() },
+			);
+			let step_environment = [
+				("UPDATEWP_STEP", step_name.as_str()),
+				("UPDATEWP_WORDPRESS_PATH", wordpress_path),
+			];
+			if let Some(ref hook) = cli_ref.pre_hook {
+				run_hook(cli_ref, runner, hook, &step_environment)?;
+			}
+			if cli_ref.maintenance {
+				// Updates turn maintenance mode off when they finish, so it's turned on again for
+				// each step.
+				set_maintenance_mode(
+					runner,
+					wordpress_path,
+					&cli_ref.wp_cli(),
+					true,
+					cli_ref.dry_run,
+					cli_ref.timeout(),
+				)?;
+			}
+			let mut items = Vec::new();
+			let result = match step {
+				Step::Core => {
+					update_core(cli_ref, runner, commit_prefix, wordpress_path, &mut items)
+				}
+				Step::Plugins => {
+					update_plugins(cli_ref, runner, commit_prefix, wordpress_path, &mut items)
+				}
+				Step::Themes => {
+					update_themes(cli_ref, runner, commit_prefix, wordpress_path, &mut items)
+				}
+				Step::Translations => {
+					update_translations(cli_ref, runner, commit_prefix, wordpress_path, &mut items)
+				}
+				Step::Database => optimize_database(cli_ref, runner, wordpress_path),
+				Step::Cache => flush_caches(cli_ref, runner, wordpress_path),
+				Step::Packages => update_packages(cli_ref, runner, wordpress_path),
+			};
+			// With --keep-going, the steps after failed plugins or themes are still run.
+			let keeps_going = cli_ref.keep_going
+				&& matches!(step, Step::Plugins | Step::Themes)
+				&& items.iter().any(|item| item.error.is_some());
+			steps.push(StepReport {
+				step: step_name.clone(),
+				items,
+				error: result.as_ref().err().map(ToString::to_string),
+				duration_secs: start.elapsed().as_secs_f64(),
+			});
+			write_event(
+				cli_ref,
+				Event {
+					event: "step_done",
+					step: &step_name,
+					error: result.as_ref().err().map(ToString::to_string).as_deref(),
+					duration_secs: Some(start.elapsed().as_secs_f64()),
+					..Default::default()
+				},
+			);
+			match result {
+				Err(error) if keeps_going => {
+					failures.push(error.to_string());
+					continue;
+				}
+				result => result?,
+			}
+			if let Some(ref hook) = cli_ref.post_hook {
+				run_hook(cli_ref, runner, hook, &step_environment)?;
+			}
+		}
+		Ok(failures)
+	})();
+	// Turn maintenance mode off even if a step failed, so the site isn't left unavailable.
+	let maintenance_result = if cli_ref.maintenance {
+		set_maintenance_mode(
+			runner,
+			wordpress_path,
+			&cli_ref.wp_cli(),
+			false,
+			cli_ref.dry_run,
+			cli_ref.timeout(),
+		)
+	} else {
+		Ok(())
+	};
+	let failures = match (steps_result, maintenance_result) {
+		(Ok(failures), maintenance_result) => maintenance_result.map(|()| failures),
+		(Err(steps_error), Ok(())) => Err(steps_error),
+		(Err(steps_error), Err(maintenance_error)) => Err(io::Error::other(format!(
+			"{steps_error}\nDeactivating maintenance mode also failed: {maintenance_error}"
+		))
+		.into()),
+	}?;
+	if cli_ref.squash {
+		squash_commit(cli_ref, runner, commit_prefix, wordpress_path, steps)?;
+	}
+	if cli_ref.push && !cli_ref.no_commit && !cli_ref.push_each_step {
+		git_push(runner, &cli_ref.git_bin, wordpress_path, &cli_ref.remote, cli_ref.dry_run)?;
+	}
+	if !failures.is_empty() {
+		return Err(io::Error::other(failures.join("\n")).into());
+	}
+	Ok(())
+}
+
+/// Commits all of the updates in `steps` at once for --squash, with a message summarising them such
+/// as "Update 12 plugins, 2 themes, core 6.4 -> 6.5".
+fn squash_commit(
+	cli: &Cli,
+	runner: &dyn CommandRunner,
+	commit_prefix: &str,
+	wordpress_path: &str,
+	steps: &mut [StepReport],
+) -> OrError<()> {
+	let mut summaries = Vec::new();
+	let mut maybe_core_versions = None;
+	for step in steps.iter() {
+		let updated: Vec<&ItemReport> =
+			step.items.iter().filter(|item| item.is_updated()).collect();
+		match step.step.as_str() {
+			"core" => {
+				if let Some(ItemReport {
+					from_version: Some(version),
+					to_version: Some(update_version),
+					..
+				}) = updated.first()
+				{
+					if version != update_version {
+						summaries.push(format!("core {version} -> {update_version}"));
+						maybe_core_versions = Some((version.as_str(), update_version.as_str()));
+					}
+				}
+			}
+			"translations" if !updated.is_empty() => {
+				summaries.push(String::from("translations"));
+			}
+			step_name @ ("plugins" | "themes") if !updated.is_empty() => {
+				let noun = step_name.strip_suffix('s').unwrap_or(step_name);
+				let plural = if updated.len() == 1 { "" } else { "s" };
+				summaries.push(format!("{} {noun}{plural}", updated.len()));
+			}
+			_ => {}
+		}
+	}
+	if summaries.is_empty() {
+		info!("Nothing was updated, so there's nothing to commit.");
+		return Ok(());
+	}
+	let message = format!("{commit_prefix}Update {}", summaries.join(", "));
+	commit(cli, runner, wordpress_path, &message, None)?;
+	if let Some((version, update_version)) = maybe_core_versions {
+		tag_core(cli, runner, wordpress_path, version, update_version)?;
+	}
+	if !cli.dry_run {
+		let maybe_sha = git_head(runner, &cli.git_bin, wordpress_path).ok();
+		for item in steps.iter_mut().flat_map(|step| &mut step.items) {
+			if item.is_updated() {
+				item.commit_sha.clone_from(&maybe_sha);
+			}
+		}
 	}
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Exit status that a process exiting with `code` would have.
+	fn exit_status(code: i32) -> ExitStatus {
+		#[cfg(unix)]
+		return std::os::unix::process::ExitStatusExt::from_raw(code << 8);
+		#[cfg(windows)]
+		return std::os::windows::process::ExitStatusExt::from_raw(code as u32);
+	}
+
+	/// Runs no commands, but records them and responds to them with canned output.
+	#[derive(Default)]
+	struct FakeRunner {
+		/// Exit code and output of the commands containing each text, checked in order. The output
+		/// is on stdout if the code is 0, or otherwise stderr. Other commands succeed silently.
+		responses: Vec<(&'static str, i32, &'static str)>,
+		/// The commands given, as displayed by `display_command`.
+		commands: Mutex<Vec<String>>,
+	}
+
+	impl FakeRunner {
+		fn new(responses: &[(&'static str, i32, &'static str)]) -> Self {
+			FakeRunner { responses: responses.to_vec(), ..Default::default() }
+		}
+
+		fn commands(&self) -> Vec<String> {
+			self.commands.lock().unwrap().clone()
+		}
+
+		fn respond(&self, command: &Command) -> Output {
+			let line = display_command(command);
+			let (code, output) = self
+				.responses
+				.iter()
+				.find(|(text, _, _)| line.contains(text))
+				.map_or((0, ""), |&(_, code, output)| (code, output));
+			self.commands.lock().unwrap().push(line);
+			let (stdout, stderr) = if code == 0 { (output, "") } else { ("", output) };
+			Output { status: exit_status(code), stdout: stdout.into(), stderr: stderr.into() }
+		}
+	}
+
+	impl CommandRunner for FakeRunner {
+		fn run_streaming(
+			&self,
+			command: &mut Command,
+			_maybe_timeout: Option<Duration>,
+		) -> OrError<Output> {
+			Ok(self.respond(command))
+		}
+
+		fn run_capture(
+			&self,
+			command: &mut Command,
+			_maybe_timeout: Option<Duration>,
+		) -> OrError<Output> {
+			Ok(self.respond(command))
+		}
+
+		fn run_status(
+			&self,
+			command: &mut Command,
+			_maybe_timeout: Option<Duration>,
+		) -> OrError<ExitStatus> {
+			Ok(self.respond(command).status)
+		}
+	}
+
+	/// Path of a directory for a test to use as the installation, which is removed when dropped.
+	struct Site(String);
+
+	impl Deref for Site {
+		type Target = str;
+
+		fn deref(&self) -> &str {
+			&self.0
+		}
+	}
+
+	impl std::fmt::Display for Site {
+		fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+			formatter.write_str(&self.0)
+		}
+	}
+
+	impl Drop for Site {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	/// Creates an empty directory for the test `name` to use as the installation.
+	fn site(name: &str) -> Site {
+		let path = env::temp_dir().join(format!("update-wp-test-{name}-{}", std::process::id()));
+		fs::create_dir_all(&path).unwrap();
+		Site(path.to_string_lossy().into_owned())
+	}
+
+	/// Parses `args` as the options given for the installation at `wordpress_path`.
+	fn cli(wordpress_path: &str, args: &[&str]) -> Cli {
+		Cli::try_parse_from(["update-wp", "--wordpress-path", wordpress_path].iter().chain(args))
+			.unwrap()
+	}
+
+	#[test]
+	fn dry_runs_run_nothing() {
+		let runner = FakeRunner::default();
+		stream_command(&runner, Command::new("wp").args(["core", "update"]), true, None).unwrap();
+		assert!(runner.commands().is_empty());
+	}
+
+	#[test]
+	fn failed_commands_fail_with_their_stderr() {
+		let runner = FakeRunner::new(&[("core version", 1, "Error: Not a WordPress site.")]);
+		let error = capture_command(&runner, Command::new("wp").args(["core", "version"]), None)
+			.unwrap_err();
+		assert!(error.to_string().contains("Error: Not a WordPress site."));
+		assert_eq!(runner.commands(), ["wp core version"]);
+	}
+
+	#[test]
+	fn placeholders_are_substituted() {
+		let placeholders = Placeholders {
+			wordpress_path: Some("/site"),
+			step: Some("update_core"),
+			unix_time: Some(1700000000),
+			date: Some(String::from("2023-11-14")),
+			name: Some("akismet"),
+			version: Some("5.1"),
+		};
+		for (template, substituted) in [
+			("{wordpress_path}", "/site"),
+			("{step}", "update_core"),
+			("{unix_time}", "1700000000"),
+			("{date}", "2023-11-14"),
+			("{name}", "akismet"),
+			("{version}", "5.1"),
+			(
+				"{wordpress_path}/../{unix_time}.{step}.{name}.{version}.sql",
+				"/site/../1700000000.update_core.akismet.5.1.sql",
+			),
+		] {
+			assert_eq!(substitute_placeholders(template, &placeholders), substituted);
+		}
+	}
+
+	#[test]
+	fn other_text_is_left_as_it_is() {
+		let placeholders = Placeholders::for_site("/site");
+		assert_eq!(substitute_placeholders("", &placeholders), "");
+		assert_eq!(substitute_placeholders("backup.sql", &placeholders), "backup.sql");
+		assert_eq!(substitute_placeholders("{unknown}{", &placeholders), "{unknown}{");
+		// Placeholders without values are left to be substituted later.
+		assert_eq!(
+			substitute_placeholders("{wordpress_path}/{unix_time}.{step}.sql", &placeholders),
+			"/site/{unix_time}.{step}.sql"
+		);
+	}
+
+	#[test]
+	fn must_use_plugins_and_drop_ins_are_not_deactivated_to_update_core() {
+		let runner = FakeRunner::new(&[
+			("core version", 0, "6.4.1"),
+			(
+				"plugin list",
+				0,
+				r#"[{"name":"akismet","status":"active"},{"name":"loader","status":"must-use"},{"name":"object-cache.php","status":"dropin"}]"#,
+			),
+		]);
+		let site = site("must-use-plugins");
+		update_core(&cli(&site, &["-b", "-c"]), &runner, "", &site, &mut Vec::new()).unwrap();
+		let commands = runner.commands();
+		assert!(commands.contains(&format!("wp --path={site} plugin deactivate akismet")));
+		assert!(commands.contains(&format!("wp --path={site} plugin activate akismet")));
+	}
+
+	#[test]
+	fn nothing_is_committed_without_changes() {
+		// `git diff --cached --quiet` succeeds, as nothing is staged.
+		let runner = FakeRunner::default();
+		git_add_commit(&runner, "git", "/site", None, "Update", &CommitOptions::default(), false)
+			.unwrap();
+		assert!(!runner.commands().iter().any(|command| command.contains(" commit ")));
+	}
+
+	#[test]
+	fn maintenance_mode_is_deactivated_after_activating_it() {
+		let site = site("maintenance-mode");
+		for core_update_code in [0, 1] {
+			let runner = FakeRunner::new(&[
+				("core version", 0, "6.4.1"),
+				("core update", core_update_code, "Error: Download failed."),
+			]);
+			let cli = cli(&site, &["-b", "-c", "--maintenance", "-s", "core"]);
+			let result = run_steps(&cli, &runner, &mut Vec::new());
+			assert_eq!(result.is_ok(), core_update_code == 0);
+			let commands = runner.commands();
+			let position =
+				|args: &str| commands.iter().position(|command| command.ends_with(args)).unwrap();
+			assert!(
+				position("maintenance-mode activate --force")
+					< position("maintenance-mode deactivate")
+			);
+		}
+	}
+
+	#[test]
+	fn plugins_are_reactivated_if_the_core_update_fails() {
+		let runner = FakeRunner::new(&[
+			("core version", 0, "6.4.1"),
+			("plugin list", 0, r#"[{"name":"akismet","status":"active"}]"#),
+			("core update", 1, "Error: Download failed."),
+		]);
+		let site = site("core-update-failure");
+		let result = update_core(&cli(&site, &["-b", "-c"]), &runner, "", &site, &mut Vec::new());
+		assert!(result.unwrap_err().to_string().contains("Error: Download failed."));
+		let commands = runner.commands();
+		let position = |command: String| commands.iter().position(|c| *c == command).unwrap();
+		assert!(
+			position(format!("wp --path={site} plugin deactivate akismet"))
+				< position(format!("wp --path={site} core update"))
+		);
+		assert!(
+			position(format!("wp --path={site} core update"))
+				< position(format!("wp --path={site} plugin activate akismet"))
+		);
+	}
+
+	#[test]
+	fn the_version_is_found_after_php_warnings() {
+		let runner = FakeRunner::new(&[(
+			"core version",
+			0,
+			"PHP Warning:  Undefined array key \"HTTP_HOST\" in wp-config.php on line 80\n6.4.1\n",
+		)]);
+		let wp_cli = cli("/site", &[]).wp_cli();
+		assert_eq!(get_wordpress_version(&runner, "/site", &wp_cli, None).unwrap(), "6.4.1");
+	}
+
+	#[test]
+	fn compressed_backups_can_be_decompressed() {
+		let site = site("compressed-backup");
+		let path = format!("{site}/backup.sql");
+		// Written as `wp db export` would.
+		let dump =
+			"-- MySQL dump\nINSERT INTO wp_options VALUES (1, 'siteurl', 'https://example.com');\n";
+		fs::write(&path, dump).unwrap();
+		let runner = FakeRunner::default();
+		let options = BackupOptions { compress: true, ..Default::default() };
+		backup_database(&runner, &site, &cli(&site, &[]).wp_cli(), &path, &options, false, None)
+			.unwrap();
+		assert!(!Path::new(&path).exists());
+		let mut decompressed = String::new();
+		GzDecoder::new(File::open(format!("{path}.gz")).unwrap())
+			.read_to_string(&mut decompressed)
+			.unwrap();
+		assert_eq!(decompressed, dump);
+	}
+
+	#[cfg(feature = "smoke-test")]
+	#[test]
+	fn server_errors_in_smoke_tests_roll_back_the_update() {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/", listener.local_addr().unwrap());
+		let server = thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut request = [0; 1024];
+			let _ = stream.read(&mut request);
+			stream
+				.write_all(b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+				.unwrap();
+		});
+		let site = site("smoke-test");
+		let backup = format!("{site}/backup.sql");
+		let runner = FakeRunner::default();
+		let result = update(
+			&cli(&site, &["--smoke-test-url", &url, "--rollback-on-failure"]),
+			&runner,
+			&site,
+			&mut ItemReport::default(),
+			Some(|| Ok(Backup { maybe_database: Some(backup.clone()), maybe_files: None })),
+			|| Ok(()),
+			None::<fn() -> OrError<()>>,
+		);
+		server.join().unwrap();
+		assert!(result.unwrap_err().to_string().contains("status 500"));
+		assert_eq!(runner.commands(), [format!("wp --path={site} db import {backup}")]);
+	}
+
+	#[test]
+	fn files_are_backed_up_before_updating_core_without_a_database_backup() {
+		let runner = FakeRunner::new(&[
+			("core version", 0, "6.4.1"),
+			("core check-update", 0, r#"[{"version":"6.5"}]"#),
+		]);
+		let site = site("core-files-backup");
+		fs::create_dir_all(format!("{site}/wp-content/plugins")).unwrap();
+		let database_file_path = format!("{site}/backups/{{step}}.sql");
+		let cli = cli(
+			&site,
+			&["-b", "-c", "--backup-files", "--database-file-path", &database_file_path],
+		);
+		let mut items = Vec::new();
+		update_core(&cli, &runner, "", &site, &mut items).unwrap();
+		assert!(runner
+			.commands()
+			.iter()
+			.any(|command| command.ends_with("core check-update --format=json")));
+		assert_eq!(items[0].files_backup, Some(format!("{site}/backups/update_core.tar")));
+		assert!(Path::new(&format!("{site}/backups/update_core.tar")).exists());
+	}
+
+	#[test]
+	fn json_is_found_among_php_notices() {
+		let update = r#"[{"name":"akismet","version":"5.0","update_version":"5.1"}]"#;
+		for output in [
+			format!("{update}\nPHP Notice: bad index []\n"),
+			format!("PHP Deprecated: Creation of dynamic property [] in foo.php\n{update}\n"),
+		] {
+			let Json { value, json, .. } = get_json::<Vec<Update>>(&output).unwrap();
+			assert_eq!(json, update);
+			assert_eq!(value[0].name, "akismet");
+		}
+	}
+
+	#[test]
+	fn json_that_isnt_as_expected_fails() {
+		let output = r#"[{"name":"akismet","version":null,"update_version":"5.1"}]"#;
+		let error = get_json::<Vec<Update>>(output).err().unwrap().to_string();
+		assert!(error.contains(output));
+		assert!(get_json::<Vec<Update>>("Success: Nothing to do.\n").unwrap().value.is_empty());
+	}
+
+	#[test]
+	fn the_default_remove_path_keeps_xdg_cache_home_literal() {
+		let default = [String::from(DEFAULT_REMOVE_PATH)];
+		// Only this test uses the variable, so setting it can't affect the others.
+		env::set_var("XDG_CACHE_HOME", "/root/.cache");
+		assert_eq!(resolve_remove_paths(&default, "/site"), ["/site/$XDG_CACHE_HOME"]);
+		env::set_var("XDG_CACHE_HOME", ".cache");
+		assert_eq!(
+			resolve_remove_paths(&default, "/site"),
+			["/site/$XDG_CACHE_HOME", "/site/.cache"]
+		);
+		env::remove_var("XDG_CACHE_HOME");
+		assert_eq!(resolve_remove_paths(&default, "/site"), ["/site/$XDG_CACHE_HOME"]);
+	}
+
+	#[test]
+	fn backups_are_pruned_from_directories_with_placeholders() {
+		let site = site("pruned-backups");
+		let backup = |date: &str, age: u64| {
+			let path = format!("{site}/backups/{date}/db.sql");
+			fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+			File::create(&path)
+				.unwrap()
+				.set_modified(SystemTime::now() - Duration::from_secs(age))
+				.unwrap();
+			path
+		};
+		let oldest = backup("2024-01-01", 300);
+		let older = backup("2024-01-02", 200);
+		let current = backup("2024-01-03", 100);
+		fs::write(format!("{site}/backups/2024-01-03/notes.txt"), "").unwrap();
+		prune_backups(&format!("{site}/backups/{{date}}/db.sql"), &current, 2, false).unwrap();
+		assert!(!Path::new(&oldest).exists());
+		assert!(Path::new(&older).exists());
+		assert!(Path::new(&current).exists());
+		assert!(Path::new(&format!("{site}/backups/2024-01-03/notes.txt")).exists());
+		// Nothing has been backed up yet.
+		prune_backups(&format!("{site}/none/{{date}}/db.sql"), &current, 2, false).unwrap();
+	}
+
+	#[test]
+	fn the_steps_after_failed_plugins_are_run_with_keep_going() {
+		let runner = FakeRunner::new(&[
+			("plugin list", 0, r#"[{"name":"akismet","version":"5.0","update_version":"5.1"}]"#),
+			("plugin update", 1, "Error: Download failed."),
+		]);
+		let site = site("keep-going");
+		let cli = cli(&site, &["-b", "-c", "--keep-going", "-s", "plugins", "-s", "themes"]);
+		let mut steps = Vec::new();
+		let error = run_steps(&cli, &runner, &mut steps).unwrap_err().to_string();
+		assert!(error.contains("akismet"));
+		assert_eq!(steps.len(), 2);
+		assert_eq!(steps[0].error.as_deref(), Some(error.as_str()));
+		assert!(runner.commands().iter().any(|command| command.contains("theme list")));
+	}
+
+	#[test]
+	fn only_changed_versions_count_as_updated() {
+		let item = |from_version: Option<&str>, to_version: Option<&str>| ItemReport {
+			from_version: from_version.map(String::from),
+			to_version: to_version.map(String::from),
+			..Default::default()
+		};
+		let steps = || {
+			vec![StepReport {
+				step: String::from("plugins"),
+				items: vec![
+					item(Some("5.0"), Some("5.1")),
+					// Reinstalled at the same version.
+					item(Some("5.0"), Some("5.0")),
+					// Translations.
+					item(None, None),
+				],
+				error: None,
+				duration_secs: 0.0,
+			}]
+		};
+		assert_eq!(RunReport::new(&cli("/site", &[]), steps(), &Ok(())).updated, 2);
+		assert_eq!(RunReport::new(&cli("/site", &["--dry-run"]), steps(), &Ok(())).updated, 0);
+	}
+}
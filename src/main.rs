@@ -1,12 +1,193 @@
-use clap::Parser;
-use std::process::Command;
-use update_wp::{main_loop, Cli, OrError};
+use std::{
+	io::{self, Write},
+	process::{Command, ExitCode},
+};
+use update_wp::{
+	check, completions, current_site, handle_interrupts, open_log_file, restore, run_and_report,
+	sites, strip_ansi, Action, Cli, OrError, ProcessRunner, RunReport, SitesReport, StepReport,
+};
 
-fn main() -> OrError<()> {
-	Command::new("wp").arg("--version").output().expect("The `wp` command isn't available");
-	Command::new("git").arg("--version").output().expect("The `git` command isn't available");
+/// Formats `seconds` like "3m12s".
+fn format_duration(seconds: f64) -> String {
+	let seconds = seconds.round() as u64;
+	match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+		(0, 0, seconds) => format!("{seconds}s"),
+		(0, minutes, seconds) => format!("{minutes}m{seconds}s"),
+		(hours, minutes, seconds) => format!("{hours}h{minutes}m{seconds}s"),
+	}
+}
+
+/// Prints what was updated in each step of the run, and how long it took.
+fn print_summary(report: &RunReport) {
+	println!("Updated {} item(s), {} failed.", report.updated, report.failed);
+	for step in &report.steps {
+		let updated = step.items.iter().filter(|item| item.is_updated()).count();
+		let failed = step.items.iter().filter(|item| item.error.is_some()).count();
+		println!(
+			"  {}: {updated} updated, {failed} failed, in {}",
+			step.step,
+			format_duration(step.duration_secs)
+		);
+		for item in &step.items {
+			let mut line = item.name.clone().unwrap_or_else(|| step.step.clone());
+			if let (Some(from_version), Some(to_version)) = (&item.from_version, &item.to_version) {
+				line += &format!(" {from_version} -> {to_version}");
+			}
+			if let Some(ref commit_sha) = item.commit_sha {
+				line += &format!(", committed as {commit_sha}");
+			}
+			if let Some(ref backup) = item.backup {
+				line += &format!(", backed up to \"{backup}\"");
+			}
+			if let Some(ref files_backup) = item.files_backup {
+				line += &format!(", files backed up to \"{files_backup}\"");
+			}
+			if let Some(duration_secs) = item.duration_secs {
+				line += &format!(", in {}", format_duration(duration_secs));
+			}
+			if let Some(ref reason) = item.not_updated {
+				line += &format!(", not updated ({reason})");
+			}
+			if let Some(ref error) = item.error {
+				line += &format!(", failed: {error}");
+			}
+			println!("    {line}");
+		}
+	}
+}
+
+/// Prints what was updated at each installation updated with the `sites` action.
+fn print_sites_summary(report: &SitesReport) {
+	println!(
+		"Updated {} item(s), {} failed, across {} site(s), {} of which failed.",
+		report.updated,
+		report.failed,
+		report.sites.len(),
+		report.failed_sites
+	);
+	for site in &report.sites {
+		let mut line =
+			format!("  {}: {} updated, {} failed", site.wordpress_path, site.updated, site.failed);
+		if let Some(ref error) = site.error {
+			line += &format!(", failed: {error}");
+		}
+		println!("{line}");
+	}
+}
+
+/// Whether the run that ended with `report` only failed as some plugins or themes failed to update,
+/// in which case its error is made of the errors of their steps.
+fn only_items_failed(report: &RunReport) -> bool {
+	let failed: Vec<&StepReport> =
+		report.steps.iter().filter(|step| step.error.is_some()).collect();
+	let errors: Vec<&str> = failed.iter().filter_map(|step| step.error.as_deref()).collect();
+	!failed.is_empty()
+		&& failed.iter().all(|step| {
+			matches!(step.step.as_str(), "plugins" | "themes")
+				&& step.items.iter().any(|item| item.error.is_some())
+		}) && report.error.as_deref() == Some(errors.join("\n").as_str())
+}
+
+/// Exit code of a run that ended with `report`, as listed in the help.
+fn exit_code(keep_going: bool, report: &RunReport) -> u8 {
+	match report.error {
+		None if report.updated == 0
+			&& (report.dry_run
+				|| !report.steps.iter().any(|step| {
+					matches!(step.step.as_str(), "database" | "cache" | "packages")
+				})) =>
+		{
+			3
+		}
+		None => 0,
+		// Only plugins and themes are kept going with when they fail.
+		Some(_) if keep_going && only_items_failed(report) => 1,
+		Some(_) => 2,
+	}
+}
 
-	let cli = Cli::parse();
+fn run() -> OrError<u8> {
+	let cli = Cli::parse_with_config()?;
+	let colors = cli.colors();
+	env_logger::Builder::new()
+		.filter_level(cli.log_level())
+		.parse_default_env()
+		.format(move |buf, record| {
+			let prefix = current_site().map(|site| format!("[{site}] ")).unwrap_or_default();
+			if colors {
+				writeln!(buf, "{prefix}{}", record.args())
+			} else {
+				writeln!(buf, "{prefix}{}", strip_ansi(&record.args().to_string()))
+			}
+		})
+		.init();
+
+	// Completion scripts can be generated without WP-CLI or Git.
+	if !matches!(cli.action, Some(Action::Completions { .. })) {
+		for (bin, option, hint) in [
+			(&cli.wp_bin, "--wp-bin", "Install WP-CLI (see https://wp-cli.org/#installing)"),
+			(&cli.git_bin, "--git-bin", "Install Git (see https://git-scm.com/downloads)"),
+		] {
+			if Command::new(bin).arg("--version").output().is_err() {
+				return Err(io::Error::other(format!(
+					"The `{bin}` command isn't available. {hint}, or set {option} to its path."
+				))
+				.into());
+			}
+		}
+		open_log_file(&cli)?;
+	}
+
+	match cli.action {
+		Some(Action::Completions { shell }) => {
+			completions(shell);
+			Ok(0)
+		}
+		Some(Action::Check) => check(cli.as_ref(), &ProcessRunner).map(|()| 0),
+		Some(Action::Restore { ref path }) => {
+			restore(cli.as_ref(), &ProcessRunner, path.as_deref()).map(|()| 0)
+		}
+		Some(Action::Sites { ref paths, ref file, site_jobs }) => {
+			handle_interrupts()?;
+			let report = sites(cli.as_ref(), &ProcessRunner, paths, file.as_deref(), site_jobs)?;
+			print_sites_summary(&report);
+			if report.failed_sites > 0 {
+				eprintln!(
+					"Error: Failed to update {} of {} site(s).",
+					report.failed_sites,
+					report.sites.len()
+				);
+			}
+			let codes: Vec<u8> =
+				report.sites.iter().map(|site| exit_code(cli.keep_going, site)).collect();
+			Ok(if codes.contains(&2) {
+				2
+			} else if codes.contains(&1) {
+				1
+			} else if codes.iter().all(|&code| code == 3) {
+				3
+			} else {
+				0
+			})
+		}
+		None => {
+			handle_interrupts()?;
+			let (report, result) = run_and_report(cli.as_ref(), &ProcessRunner)?;
+			print_summary(&report);
+			if let Err(error) = result {
+				eprintln!("Error: {error}");
+			}
+			Ok(exit_code(cli.keep_going, &report))
+		}
+	}
+}
 
-	main_loop(cli.as_ref())
+fn main() -> ExitCode {
+	match run() {
+		Ok(code) => ExitCode::from(code),
+		Err(error) => {
+			eprintln!("Error: {error}");
+			ExitCode::from(2)
+		}
+	}
 }